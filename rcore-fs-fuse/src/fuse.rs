@@ -5,6 +5,7 @@ use fuse::{
 use rcore_fs::vfs;
 use std::collections::btree_map::BTreeMap;
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::sync::Arc;
 use time::Timespec;
 
@@ -43,7 +44,7 @@ impl VfsFuse {
             ctime: Self::trans_time(info.ctime),
             crtime: Timespec { sec: 0, nsec: 0 },
             kind: Self::trans_type(info.type_),
-            perm: info.mode,
+            perm: info.mode & 0o7777,
             nlink: info.nlinks as u32,
             uid: 501, // info.uid as u32,
             gid: 20,  // info.gid as u32,
@@ -77,6 +78,11 @@ impl VfsFuse {
             vfs::FsError::DirRemoved => ENOENT,
             vfs::FsError::DirNotEmpty => ENOTEMPTY,
             vfs::FsError::WrongFs => EINVAL,
+            vfs::FsError::TimedOut => ETIMEDOUT,
+            vfs::FsError::BadFd => EBADF,
+            vfs::FsError::OperationNotPermitted => EPERM,
+            vfs::FsError::Corrupted => EBADMSG,
+            vfs::FsError::ReadOnlyFs => EROFS,
             _ => EINVAL,
         }
     }
@@ -108,7 +114,7 @@ impl Filesystem for VfsFuse {
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let inode = try_vfs!(reply, self.get_inode(parent));
-        let target = try_vfs!(reply, inode.lookup(name.to_str().unwrap()));
+        let target = try_vfs!(reply, inode.find_bytes(name.as_bytes()));
         let info = try_vfs!(reply, target.metadata());
         self.inodes.insert(info.inode, target);
         let attr = Self::trans_attr(info);
@@ -145,7 +151,7 @@ impl Filesystem for VfsFuse {
         }
         let mut info = try_vfs!(reply, inode.metadata());
         if let Some(mode) = mode {
-            info.mode = mode as u16;
+            info.mode = (mode & 0o7777) as u16;
         }
         if let Some(uid) = uid {
             info.uid = uid as usize;
@@ -173,9 +179,11 @@ impl Filesystem for VfsFuse {
         _rdev: u32,
         reply: ReplyEntry,
     ) {
-        let name = name.to_str().unwrap();
+        let name = name.as_bytes();
         let inode = try_vfs!(reply, self.get_inode(parent));
-        let target = try_vfs!(reply, inode.create(name, vfs::FileType::File, mode));
+        // drop the file type bits
+        let mode = mode & 0o7777;
+        let target = try_vfs!(reply, inode.create_bytes(name, vfs::FileType::File, mode));
         let info = try_vfs!(reply, target.metadata());
         self.inodes.insert(info.inode, target);
         let attr = Self::trans_attr(info);
@@ -183,9 +191,9 @@ impl Filesystem for VfsFuse {
     }
 
     fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
-        let name = name.to_str().unwrap();
+        let name = name.as_bytes();
         let inode = try_vfs!(reply, self.get_inode(parent));
-        let target = try_vfs!(reply, inode.create(name, vfs::FileType::Dir, mode));
+        let target = try_vfs!(reply, inode.create_bytes(name, vfs::FileType::Dir, mode));
         let info = try_vfs!(reply, target.metadata());
         self.inodes.insert(info.inode, target);
         let attr = Self::trans_attr(info);
@@ -193,9 +201,9 @@ impl Filesystem for VfsFuse {
     }
 
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name = name.to_str().unwrap();
+        let name = name.as_bytes();
         let parent = try_vfs!(reply, self.get_inode(parent));
-        try_vfs!(reply, parent.unlink(name));
+        try_vfs!(reply, parent.unlink_bytes(name));
         reply.ok();
     }
 
@@ -212,11 +220,11 @@ impl Filesystem for VfsFuse {
         newname: &OsStr,
         reply: ReplyEmpty,
     ) {
-        let name = name.to_str().unwrap();
-        let newname = newname.to_str().unwrap();
+        let name = name.as_bytes();
+        let newname = newname.as_bytes();
         let parent = try_vfs!(reply, self.get_inode(parent));
         let newparent = try_vfs!(reply, self.get_inode(newparent));
-        try_vfs!(reply, parent.move_(name, newparent, newname));
+        try_vfs!(reply, parent.move_bytes(name, newparent, newname));
         reply.ok();
     }
 
@@ -228,10 +236,10 @@ impl Filesystem for VfsFuse {
         newname: &OsStr,
         reply: ReplyEntry,
     ) {
-        let newname = newname.to_str().unwrap();
+        let newname = newname.as_bytes();
         let inode = try_vfs!(reply, self.get_inode(ino));
         let newparent = try_vfs!(reply, self.get_inode(newparent));
-        try_vfs!(reply, newparent.link(newname, inode));
+        try_vfs!(reply, newparent.link_bytes(newname, inode));
         let info = try_vfs!(reply, inode.metadata());
         let attr = Self::trans_attr(info);
         reply.entry(&TTL, &attr, 0);
@@ -294,15 +302,20 @@ impl Filesystem for VfsFuse {
     ) {
         let inode = try_vfs!(reply, self.get_inode(ino));
         for i in offset as usize.. {
-            let name = match inode.get_entry(i) {
+            let name = match inode.get_entry_bytes(i) {
                 Ok(name) => name,
                 Err(vfs::FsError::EntryNotFound) => break,
                 e @ _ => try_vfs!(reply, e),
             };
-            let inode = try_vfs!(reply, inode.find(name.as_str()));
+            let inode = try_vfs!(reply, inode.find_bytes(&name));
             let info = try_vfs!(reply, inode.metadata());
             let kind = Self::trans_type(info.type_);
-            let full = reply.add(info.inode as u64, i as i64 + 1, kind, name);
+            let full = reply.add(
+                info.inode as u64,
+                i as i64 + 1,
+                kind,
+                OsStr::from_bytes(&name),
+            );
             if full {
                 break;
             }
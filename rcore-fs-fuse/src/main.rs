@@ -4,16 +4,22 @@ use std::sync::{Arc, Mutex};
 
 use structopt::StructOpt;
 
+#[cfg(unix)]
+use rcore_fs::dev::std_impl::StdRngProvider;
 use rcore_fs::dev::std_impl::StdTimeProvider;
-use rcore_fs::vfs::FileSystem;
+use rcore_fs::dev::write_combine::WriteCombine;
+use rcore_fs::dev::{FixedTimeProvider, RngProvider, SeededRngProvider, TimeProvider};
+use rcore_fs::vfs::{FileSystem, Timespec};
 #[cfg(feature = "use_fuse")]
 use rcore_fs_fuse::fuse::VfsFuse;
-use rcore_fs_fuse::zip::{unzip_dir, zip_dir};
+use rcore_fs_fuse::zip::{unzip_dir, zip_dir_parallel};
+use rcore_fs_hostfs as hostfs;
 use rcore_fs_ramfs as ramfs;
 use rcore_fs_sefs as sefs;
 use rcore_fs_sfs as sfs;
 
 use git_version::git_version;
+use lazy_static::lazy_static;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
@@ -29,9 +35,13 @@ struct Opt {
     #[structopt(parse(from_os_str))]
     dir: PathBuf,
 
-    /// File system: [sfs | sefs | ramfs]
+    /// File system: [sfs | sefs | ramfs | hostfs]
     #[structopt(short = "f", long = "fs", default_value = "sfs")]
     fs: String,
+
+    /// Number of threads copying files when zipping
+    #[structopt(short = "j", long = "jobs", default_value = "1")]
+    jobs: usize,
 }
 
 #[derive(Debug, StructOpt)]
@@ -53,6 +63,46 @@ enum Cmd {
     GitVersion,
 }
 
+lazy_static! {
+    /// `SOURCE_DATE_EPOCH` if set, for reproducible images
+    static ref SOURCE_DATE_EPOCH: Option<u64> = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .map(|epoch| epoch.parse().expect("invalid SOURCE_DATE_EPOCH"));
+    static ref FIXED_TIME: Option<FixedTimeProvider> = SOURCE_DATE_EPOCH.map(|sec| {
+        FixedTimeProvider(Timespec {
+            sec: sec as i64,
+            nsec: 0,
+        })
+    });
+    static ref SEEDED_RNG: Option<SeededRngProvider> = match *SOURCE_DATE_EPOCH {
+        Some(seed) => Some(SeededRngProvider::new(seed)),
+        #[cfg(unix)]
+        None => None,
+        // no StdRngProvider, seed with the time
+        #[cfg(not(unix))]
+        None => Some(SeededRngProvider::new(StdTimeProvider.current_time().nsec as u64)),
+    };
+}
+
+/// Use `SOURCE_DATE_EPOCH` as the time if set, for reproducible images
+fn time_provider() -> &'static dyn TimeProvider {
+    match &*FIXED_TIME {
+        Some(time) => time,
+        None => &StdTimeProvider,
+    }
+}
+
+/// Seed the random numbers with `SOURCE_DATE_EPOCH` if set, for reproducible images
+fn rng_provider() -> &'static dyn RngProvider {
+    match &*SEEDED_RNG {
+        Some(rng) => rng,
+        #[cfg(unix)]
+        None => &StdRngProvider,
+        #[cfg(not(unix))]
+        None => unreachable!(),
+    }
+}
+
 fn main() {
     env_logger::init().unwrap();
     let opt = Opt::from_args();
@@ -78,7 +128,9 @@ fn main() {
                 .truncate(create)
                 .open(&opt.image)
                 .expect("failed to open image");
-            let device = Mutex::new(file);
+            // merge the many small metadata writes of a bulk create
+            const WRITE_WINDOW: usize = 0x10_0000; // 1M
+            let device = WriteCombine::new(Mutex::new(file), WRITE_WINDOW);
             const MAX_SPACE: usize = 0x1000 * 0x1000 * 1024; // 1G
             match create {
                 true => sfs::SimpleFileSystem::create(Arc::new(device), MAX_SPACE)
@@ -90,13 +142,18 @@ fn main() {
             std::fs::create_dir_all(&opt.image).unwrap();
             let device = sefs::dev::StdStorage::new(&opt.image);
             match create {
-                true => sefs::SEFS::create(Box::new(device), &StdTimeProvider)
+                true => sefs::SEFS::create(Box::new(device), time_provider(), rng_provider())
                     .expect("failed to create sefs"),
-                false => sefs::SEFS::open(Box::new(device), &StdTimeProvider)
+                false => sefs::SEFS::open(Box::new(device), time_provider())
                     .expect("failed to open sefs"),
             }
         }
         "ramfs" => ramfs::RamFS::new(),
+        // pass through the host directory <image>
+        "hostfs" => {
+            std::fs::create_dir_all(&opt.image).unwrap();
+            hostfs::HostFS::new(&opt.image)
+        }
         _ => panic!("unsupported file system"),
     };
     match opt.cmd {
@@ -105,7 +162,7 @@ fn main() {
             fuse::mount(VfsFuse::new(fs), &opt.dir, &[]).expect("failed to mount fs");
         }
         Cmd::Zip => {
-            zip_dir(&opt.dir, fs.root_inode()).expect("failed to zip fs");
+            zip_dir_parallel(&opt.dir, fs.root_inode(), opt.jobs).expect("failed to zip fs");
         }
         Cmd::Unzip => {
             std::fs::create_dir(&opt.dir).expect("failed to create dir");
@@ -1,43 +1,111 @@
 use std::error::Error;
+#[cfg(unix)]
+use std::ffi::OsStr;
 use std::fs;
 use std::io::{Read, Write};
 use std::mem::MaybeUninit;
 #[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+#[cfg(windows)]
 use std::str;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use rcore_fs::vfs::{FileType, INode};
 
+#[cfg(windows)]
 const DEFAULT_MODE: u32 = 0o664;
 const BUF_SIZE: usize = 0x1000;
 
+/// Pack the host directory `path` into `inode`
 pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
-    let dir = fs::read_dir(path)?;
-    for entry in dir {
-        let entry = entry?;
+    zip_dir_parallel(path, inode, 1)
+}
+
+/// Pack the host directory `path` into `inode`, copying file contents with `jobs` threads.
+///
+/// Entries are created in name order and files are allocated before copying,
+/// so the result does not depend on `jobs` or the order of the host directory.
+pub fn zip_dir_parallel(
+    path: &Path,
+    inode: Arc<dyn INode>,
+    jobs: usize,
+) -> Result<(), Box<dyn Error>> {
+    let mut files = Vec::new();
+    create_tree(path, &inode, &mut Vec::new(), &mut files)?;
+    if jobs <= 1 {
+        for (path, names) in files {
+            copy_file(&path, &find_path(&inode, &names)?)?;
+        }
+        return Ok(());
+    }
+    let files = Arc::new(Mutex::new(files.into_iter()));
+    let workers: Vec<_> = (0..jobs)
+        .map(|_| {
+            let (files, root) = (files.clone(), inode.clone());
+            thread::spawn(move || -> Result<(), String> {
+                loop {
+                    let next = files.lock().unwrap().next();
+                    match next {
+                        Some((path, names)) => find_path(&root, &names)
+                            .and_then(|inode| copy_file(&path, &inode))
+                            .map_err(|e| e.to_string())?,
+                        None => return Ok(()),
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+    Ok(())
+}
+
+/// A host file to copy, and the names of its path in the image
+type FileToCopy = (PathBuf, Vec<Vec<u8>>);
+
+/// Create entries of `path` in `inode` recursively, and collect the files to copy.
+///
+/// Only paths are collected, INodes may hold host resources like open files.
+fn create_tree(
+    path: &Path,
+    inode: &Arc<dyn INode>,
+    names: &mut Vec<Vec<u8>>,
+    files: &mut Vec<FileToCopy>,
+) -> Result<(), Box<dyn Error>> {
+    let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
         let name_ = entry.file_name();
-        let name = name_.to_str().unwrap();
+        // keep the name bytes as is
+        #[cfg(unix)]
+        let name = name_.as_bytes();
+        #[cfg(windows)]
+        let name = name_.to_str().unwrap().as_bytes();
         let type_ = entry.file_type()?;
+        // keep the host permissions
+        #[cfg(unix)]
+        let mode = entry.metadata()?.permissions().mode() & 0o7777;
+        #[cfg(windows)]
+        let mode = DEFAULT_MODE;
         if type_.is_file() {
-            let inode = inode.create(name, FileType::File, DEFAULT_MODE)?;
-            let mut file = fs::File::open(entry.path())?;
-            inode.resize(file.metadata()?.len() as usize)?;
-            let mut buf: [u8; BUF_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
-            let mut offset = 0usize;
-            let mut len = BUF_SIZE;
-            while len == BUF_SIZE {
-                len = file.read(&mut buf)?;
-                inode.write_at(offset, &buf[..len])?;
-                offset += len;
-            }
+            let inode = inode.create_bytes(name, FileType::File, mode)?;
+            inode.resize(entry.metadata()?.len() as usize)?;
+            let mut names = names.clone();
+            names.push(name.to_vec());
+            files.push((entry.path(), names));
         } else if type_.is_dir() {
-            let inode = inode.create(name, FileType::Dir, DEFAULT_MODE)?;
-            zip_dir(entry.path().as_path(), inode)?;
+            let inode = inode.create_bytes(name, FileType::Dir, mode)?;
+            names.push(name.to_vec());
+            create_tree(entry.path().as_path(), &inode, names, files)?;
+            names.pop();
         } else if type_.is_symlink() {
             let target = fs::read_link(entry.path())?;
-            let inode = inode.create(name, FileType::SymLink, DEFAULT_MODE)?;
+            let inode = inode.create_bytes(name, FileType::SymLink, mode)?;
             #[cfg(unix)]
             let data = target.as_os_str().as_bytes();
             #[cfg(windows)]
@@ -49,12 +117,38 @@ pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>>
     Ok(())
 }
 
+/// Find the INode at `names` from `root`
+fn find_path(root: &Arc<dyn INode>, names: &[Vec<u8>]) -> Result<Arc<dyn INode>, Box<dyn Error>> {
+    let mut inode = root.clone();
+    for name in names {
+        inode = inode.find_bytes(name)?;
+    }
+    Ok(inode)
+}
+
+/// Copy the content of host file `path` to `inode`
+fn copy_file(path: &Path, inode: &Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::File::open(path)?;
+    let mut buf: [u8; BUF_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
+    let mut offset = 0usize;
+    let mut len = BUF_SIZE;
+    while len == BUF_SIZE {
+        len = file.read(&mut buf)?;
+        inode.write_at(offset, &buf[..len])?;
+        offset += len;
+    }
+    Ok(())
+}
+
 pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
-    let files = inode.list()?;
+    let files = inode.list_bytes()?;
     for name in files.iter().skip(2) {
-        let inode = inode.lookup(name.as_str())?;
+        let inode = inode.find_bytes(name)?;
         let mut path = path.to_path_buf();
-        path.push(name);
+        #[cfg(unix)]
+        path.push(OsStr::from_bytes(name));
+        #[cfg(windows)]
+        path.push(str::from_utf8(name)?);
         let info = inode.metadata()?;
         match info.type_ {
             FileType::File => {
@@ -76,7 +170,7 @@ pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error
                 let mut buf: [u8; BUF_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
                 let len = inode.read_at(0, buf.as_mut())?;
                 #[cfg(unix)]
-                std::os::unix::fs::symlink(str::from_utf8(&buf[..len]).unwrap(), path)?;
+                std::os::unix::fs::symlink(OsStr::from_bytes(&buf[..len]), path)?;
                 #[cfg(windows)]
                 std::os::windows::fs::symlink_file(str::from_utf8(&buf[..len]).unwrap(), path)?;
             }
@@ -85,3 +179,72 @@ pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcore_fs::dev::{FixedTimeProvider, SeededRngProvider};
+    use rcore_fs::vfs::{FileSystem, Timespec};
+    use rcore_fs_sefs as sefs;
+    use rcore_fs_sfs as sfs;
+    use tempfile::TempDir;
+
+    /// A host tree with files of different sizes, so the workers interleave
+    fn host_tree() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..4 {
+            let sub = dir.path().join(format!("dir{}", i));
+            fs::create_dir(&sub).unwrap();
+            for j in 0..8 {
+                let data = vec![(i * 8 + j) as u8; (j * 3 + i) * 1000 + 1];
+                fs::write(sub.join(format!("file{}", j)), data).unwrap();
+            }
+        }
+        dir
+    }
+
+    /// Zip `tree` into a new SFS image with `jobs` threads, return the image
+    fn zip_sfs(tree: &Path, jobs: usize) -> Vec<u8> {
+        let image = tempfile::NamedTempFile::new().unwrap();
+        let file = image.reopen().unwrap();
+        let fs = sfs::SimpleFileSystem::create(Arc::new(Mutex::new(file)), 0x100_0000).unwrap();
+        zip_dir_parallel(tree, fs.root_inode(), jobs).unwrap();
+        fs.sync().unwrap();
+        drop(fs);
+        fs::read(image.path()).unwrap()
+    }
+
+    /// Zip `tree` into a new SEFS image with `jobs` threads, return its files
+    fn zip_sefs(
+        tree: &Path,
+        jobs: usize,
+        rng: &'static SeededRngProvider,
+    ) -> Vec<(PathBuf, Vec<u8>)> {
+        static TIME: FixedTimeProvider = FixedTimeProvider(Timespec { sec: 1, nsec: 0 });
+        let image = tempfile::tempdir().unwrap();
+        let device = sefs::dev::StdStorage::new(image.path());
+        let fs = sefs::SEFS::create(Box::new(device), &TIME, rng).unwrap();
+        zip_dir_parallel(tree, fs.root_inode(), jobs).unwrap();
+        fs.sync().unwrap();
+        drop(fs);
+        let mut files: Vec<_> = fs::read_dir(image.path())
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let data = fs::read(&path).unwrap();
+                (path.strip_prefix(image.path()).unwrap().to_path_buf(), data)
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn parallel_zip_is_reproducible() {
+        let tree = host_tree();
+        assert!(zip_sfs(tree.path(), 1) == zip_sfs(tree.path(), 4));
+        // the same seed for both
+        static RNG: [SeededRngProvider; 2] = [SeededRngProvider::new(1), SeededRngProvider::new(1)];
+        assert!(zip_sefs(tree.path(), 1, &RNG[0]) == zip_sefs(tree.path(), 4, &RNG[1]));
+    }
+}
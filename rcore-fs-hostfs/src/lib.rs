@@ -2,7 +2,14 @@
 
 use core::any::Any;
 use rcore_fs::vfs::*;
+use std::ffi::{OsStr, OsString};
 use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::string::String;
 use std::sync::{Arc, Weak};
@@ -11,6 +18,9 @@ use std::sync::{Mutex, MutexGuard};
 #[macro_use]
 extern crate log;
 
+#[cfg(test)]
+mod tests;
+
 /// File system at host
 pub struct HostFS {
     path: PathBuf,
@@ -21,12 +31,26 @@ pub struct HostFS {
 pub struct HNode {
     path: PathBuf,
     file: Mutex<Option<std::fs::File>>,
+    /// Sorted names of a directory, taken when reading entry 0
+    entries: Mutex<Option<Vec<OsString>>>,
     fs: Arc<HostFS>,
 }
 
 impl FileSystem for HostFS {
     fn sync(&self) -> Result<()> {
-        warn!("HostFS: sync is unimplemented");
+        // writes go to the host directly, ask the host to flush the file system holding them
+        #[cfg(unix)]
+        {
+            let root = std::fs::File::open(&self.path)?;
+            #[cfg(target_os = "linux")]
+            {
+                if unsafe { libc::syncfs(root.as_raw_fd()) } != 0 {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            root.sync_all()?;
+        }
         Ok(())
     }
 
@@ -34,12 +58,43 @@ impl FileSystem for HostFS {
         Arc::new(HNode {
             path: self.path.clone(),
             file: Mutex::new(None),
+            entries: Mutex::new(None),
             fs: self.self_ref.upgrade().unwrap(),
         })
     }
 
+    #[cfg(unix)]
     fn info(&self) -> FsInfo {
-        unimplemented!()
+        let path = std::ffi::CString::new(self.path.as_os_str().as_bytes()).unwrap();
+        let mut stat: libc::statvfs = unsafe { core::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            warn!("HostFS: statvfs failed on {:?}", self.path);
+        }
+        FsInfo {
+            bsize: stat.f_bsize as usize,
+            frsize: stat.f_frsize as usize,
+            blocks: stat.f_blocks as usize,
+            bfree: stat.f_bfree as usize,
+            bavail: stat.f_bavail as usize,
+            files: stat.f_files as usize,
+            ffree: stat.f_ffree as usize,
+            namemax: stat.f_namemax as usize,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn info(&self) -> FsInfo {
+        warn!("HostFS: info() is unimplemented");
+        FsInfo {
+            bsize: 0,
+            frsize: 0,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: 255,
+        }
     }
 }
 
@@ -67,6 +122,14 @@ impl HostFS {
 
 impl INode for HNode {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.path.symlink_metadata()?.file_type().is_symlink() {
+            // the content of a symlink is its target
+            let target = std::fs::read_link(&self.path)?;
+            let target = os_str_bytes(target.as_os_str())?;
+            let len = buf.len().min(target.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&target[offset..offset + len]);
+            return Ok(len);
+        }
         let mut guard = self.open_file()?;
         let file = guard.as_mut().unwrap();
         file.seek(SeekFrom::Start(offset as u64))?;
@@ -82,17 +145,47 @@ impl INode for HNode {
         Ok(len)
     }
 
+    fn append(&self, buf: &[u8]) -> Result<(usize, usize)> {
+        let mut guard = self.open_file()?;
+        let file = guard.as_mut().unwrap();
+        let offset = file.seek(SeekFrom::End(0))?;
+        let len = file.write(buf)?;
+        Ok((offset as usize, len))
+    }
+
     fn poll(&self) -> Result<PollStatus> {
-        unimplemented!()
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
     }
 
     fn metadata(&self) -> Result<Metadata> {
-        let metadata = self.path.metadata()?;
+        let metadata = self.path.symlink_metadata()?;
         Ok(metadata.into())
     }
 
-    fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
-        warn!("HostFS: set_metadata() is unimplemented");
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        let old = self.metadata()?;
+        #[cfg(unix)]
+        {
+            if metadata.mode != old.mode {
+                let permissions = std::fs::Permissions::from_mode(metadata.mode as u32 & 0o7777);
+                std::fs::set_permissions(&self.path, permissions)?;
+            }
+            if (metadata.uid, metadata.gid) != (old.uid, old.gid) {
+                let path = std::ffi::CString::new(self.path.as_os_str().as_bytes()).unwrap();
+                if unsafe { libc::chown(path.as_ptr(), metadata.uid as _, metadata.gid as _) } != 0
+                {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+            }
+        }
+        if (metadata.atime, metadata.mtime) != (old.atime, old.mtime) {
+            let time = |t: Timespec| filetime::FileTime::from_unix_time(t.sec, t.nsec as u32);
+            filetime::set_file_times(&self.path, time(metadata.atime), time(metadata.mtime))?;
+        }
         Ok(())
     }
 
@@ -117,76 +210,60 @@ impl INode for HNode {
         Ok(())
     }
 
-    fn create(&self, name: &str, type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
-        let new_path = self.path.join(name);
-        if new_path.exists() {
-            return Err(FsError::EntryExist);
-        }
-        match type_ {
-            FileType::File => {
-                std::fs::File::create(&new_path)?;
-            }
-            FileType::Dir => {
-                std::fs::create_dir(&new_path)?;
-            }
-            _ => unimplemented!("only support creating file or dir in HostFS"),
-        }
-        Ok(Arc::new(HNode {
-            path: new_path,
-            file: Mutex::new(None),
-            fs: self.fs.clone(),
-        }))
+    fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        self.create_os(OsStr::new(name), type_, mode)
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
-        let other = other.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
-        std::fs::hard_link(&other.path, &self.path.join(name))?;
-        Ok(())
+        self.link_os(OsStr::new(name), other)
     }
 
     fn unlink(&self, name: &str) -> Result<()> {
-        let new_path = self.path.join(name);
-        if new_path.is_file() {
-            std::fs::remove_file(new_path)?;
-        } else if new_path.is_dir() {
-            std::fs::remove_dir(new_path)?;
-        } else {
-            return Err(FsError::EntryNotFound);
-        }
-        Ok(())
+        self.unlink_os(OsStr::new(name))
     }
 
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
-        let target = target.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
-        let old_path = self.path.join(old_name);
-        let new_path = target.path.join(new_name);
-        std::fs::rename(old_path, new_path)?;
-        Ok(())
+        self.move_os(OsStr::new(old_name), target, OsStr::new(new_name))
     }
 
     fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
-        let new_path = self.path.join(name);
-        if !new_path.exists() {
-            return Err(FsError::EntryNotFound);
-        }
-        Ok(Arc::new(HNode {
-            path: new_path,
-            file: Mutex::new(None),
-            fs: self.fs.clone(),
-        }))
+        self.find_os(OsStr::new(name))
     }
 
+    /// Non UTF-8 names are converted lossily, use `get_entry_bytes()` to get the exact name.
     fn get_entry(&self, id: usize) -> Result<String> {
-        if !self.path.is_dir() {
-            return Err(FsError::NotDir);
-        }
-        self.path
-            .read_dir()?
-            .nth(id)
-            .ok_or(FsError::EntryNotFound)??
-            .file_name()
-            .into_string()
-            .map_err(|_| FsError::InvalidParam)
+        Ok(self.get_entry_os(id)?.to_string_lossy().into_owned())
+    }
+
+    #[cfg(unix)]
+    fn find_bytes(&self, name: &[u8]) -> Result<Arc<dyn INode>> {
+        self.find_os(OsStr::from_bytes(name))
+    }
+
+    #[cfg(unix)]
+    fn create_bytes(&self, name: &[u8], type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        self.create_os(OsStr::from_bytes(name), type_, mode)
+    }
+
+    #[cfg(unix)]
+    fn link_bytes(&self, name: &[u8], other: &Arc<dyn INode>) -> Result<()> {
+        self.link_os(OsStr::from_bytes(name), other)
+    }
+
+    #[cfg(unix)]
+    fn unlink_bytes(&self, name: &[u8]) -> Result<()> {
+        self.unlink_os(OsStr::from_bytes(name))
+    }
+
+    #[cfg(unix)]
+    fn move_bytes(&self, old_name: &[u8], target: &Arc<dyn INode>, new_name: &[u8]) -> Result<()> {
+        let (old_name, new_name) = (OsStr::from_bytes(old_name), OsStr::from_bytes(new_name));
+        self.move_os(old_name, target, new_name)
+    }
+
+    #[cfg(unix)]
+    fn get_entry_bytes(&self, id: usize) -> Result<Vec<u8>> {
+        Ok(self.get_entry_os(id)?.into_vec())
     }
 
     fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
@@ -209,23 +286,175 @@ impl INode for HNode {
 impl HNode {
     /// Ensure to open the file and store a `File` into `self.file`,
     /// return the `MutexGuard`.
-    /// If the type of `self.path` is not file, then return Err
+    /// If the type of `self.path` is not file, then return Err.
+    /// Symlinks are not followed.
     fn open_file(&self) -> Result<MutexGuard<Option<std::fs::File>>> {
-        if !self.path.exists() {
-            return Err(FsError::EntryNotFound);
-        }
-        if !self.path.is_file() {
+        let metadata = self
+            .path
+            .symlink_metadata()
+            .map_err(|_| FsError::EntryNotFound)?;
+        if !metadata.is_file() {
             return Err(FsError::NotFile);
         }
         let mut maybe_file = self.file.lock().unwrap();
         if maybe_file.is_none() {
-            let file = std::fs::OpenOptions::new()
-                .read(true)
+            let mut options = std::fs::OpenOptions::new();
+            options.read(true);
+            // in case it is replaced by a symlink meanwhile
+            #[cfg(unix)]
+            options.custom_flags(libc::O_NOFOLLOW);
+            let file = options
+                .clone()
                 .write(true)
-                .create(true)
-                .open(&self.path)?;
+                .open(&self.path)
+                // fall back to read-only, writes will fail
+                .or_else(|_| options.open(&self.path))?;
             *maybe_file = Some(file);
         }
         Ok(maybe_file)
     }
+
+    /// Check this is a directory, not a symlink to one
+    fn check_dir(&self) -> Result<()> {
+        let metadata = self
+            .path
+            .symlink_metadata()
+            .map_err(|_| FsError::EntryNotFound)?;
+        match metadata.is_dir() {
+            true => Ok(()),
+            false => Err(FsError::NotDir),
+        }
+    }
+
+    fn new_node(&self, path: PathBuf) -> Arc<dyn INode> {
+        Arc::new(HNode {
+            path,
+            file: Mutex::new(None),
+            entries: Mutex::new(None),
+            fs: self.fs.clone(),
+        })
+    }
+
+    /// Host path of the entry `name` in this directory
+    fn child(&self, name: &OsStr) -> Result<PathBuf> {
+        self.check_dir()?;
+        check_name(&os_str_bytes(name)?)?;
+        Ok(self.path.join(name))
+    }
+
+    fn find_os(&self, name: &OsStr) -> Result<Arc<dyn INode>> {
+        self.check_dir()?;
+        if name == "." || name == "" {
+            return Ok(self.new_node(self.path.clone()));
+        }
+        if name == ".." {
+            // do not go beyond the root
+            let path = match self.path == self.fs.path {
+                true => self.path.clone(),
+                false => self.path.parent().unwrap().to_path_buf(),
+            };
+            return Ok(self.new_node(path));
+        }
+        let path = self.child(name).map_err(|_| FsError::EntryNotFound)?;
+        if path.symlink_metadata().is_err() {
+            return Err(FsError::EntryNotFound);
+        }
+        Ok(self.new_node(path))
+    }
+
+    fn create_os(&self, name: &OsStr, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        let new_path = self.child(name)?;
+        // both fail with `EntryExist` instead of replacing an existing entry
+        match type_ {
+            FileType::File => {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&new_path)?;
+            }
+            FileType::Dir => {
+                std::fs::create_dir(&new_path)?;
+            }
+            // the target of a symlink can not be written later
+            _ => return Err(FsError::NotSupported),
+        }
+        #[cfg(unix)]
+        std::fs::set_permissions(&new_path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+        #[cfg(not(unix))]
+        let _ = mode;
+        Ok(self.new_node(new_path))
+    }
+
+    fn link_os(&self, name: &OsStr, other: &Arc<dyn INode>) -> Result<()> {
+        let other = other.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        std::fs::hard_link(&other.path, &self.child(name)?)?;
+        Ok(())
+    }
+
+    fn unlink_os(&self, name: &OsStr) -> Result<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let new_path = self.child(name).map_err(|_| FsError::EntryNotFound)?;
+        let type_ = new_path.symlink_metadata()?.file_type();
+        if type_.is_dir() {
+            std::fs::remove_dir(new_path)?;
+        } else {
+            std::fs::remove_file(new_path)?;
+        }
+        Ok(())
+    }
+
+    fn move_os(&self, old_name: &OsStr, target: &Arc<dyn INode>, new_name: &OsStr) -> Result<()> {
+        let target = target.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        if old_name == "." || old_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let old_path = self.child(old_name).map_err(|_| FsError::EntryNotFound)?;
+        let new_path = target.child(new_name)?;
+        if new_path.symlink_metadata().is_ok() {
+            return Err(FsError::EntryExist);
+        }
+        std::fs::rename(old_path, new_path)?;
+        Ok(())
+    }
+
+    /// Entries are sorted by name. The listing is taken again when reading
+    /// entry 0, so changes on the host show up in the next pass.
+    fn get_entry_os(&self, id: usize) -> Result<OsString> {
+        self.check_dir()?;
+        let mut entries = self.entries.lock().unwrap();
+        if id == 0 || entries.is_none() {
+            let mut names = self
+                .path
+                .read_dir()?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            names.sort();
+            *entries = Some(names);
+        }
+        // the host does not list "." and ".."
+        match id {
+            0 => Ok(".".into()),
+            1 => Ok("..".into()),
+            _ => entries
+                .as_ref()
+                .unwrap()
+                .get(id - 2)
+                .cloned()
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+}
+
+/// Raw bytes of a host name
+#[cfg(unix)]
+fn os_str_bytes(s: &OsStr) -> Result<Vec<u8>> {
+    Ok(s.as_bytes().to_vec())
+}
+
+/// Raw bytes of a host name, UTF-8 only
+#[cfg(not(unix))]
+fn os_str_bytes(s: &OsStr) -> Result<Vec<u8>> {
+    Ok(s.to_str().ok_or(FsError::InvalidParam)?.as_bytes().to_vec())
 }
@@ -0,0 +1,160 @@
+use crate::*;
+use rcore_fs_mountfs::{MNode, MountFS};
+use rcore_fs_ramfs::RamFS;
+
+#[test]
+fn read_write() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let fs = HostFS::new(dir.path());
+    let root = fs.root_inode();
+    let file = root.create("file", FileType::File, 0o640)?;
+    assert_eq!(file.write_at(2, b"hello")?, 5);
+    assert_eq!(
+        std::fs::read(dir.path().join("file")).unwrap(),
+        b"\0\0hello"
+    );
+    let mut buf = [0u8; 5];
+    assert_eq!(file.read_at(2, &mut buf)?, 5);
+    assert_eq!(&buf, b"hello");
+    file.resize(3)?;
+    assert_eq!(file.metadata()?.size, 3);
+    #[cfg(unix)]
+    assert_eq!(file.metadata()?.mode, 0o640);
+    assert_eq!(
+        root.create("file", FileType::File, 0o640).err(),
+        Some(FsError::EntryExist)
+    );
+    assert_eq!(file.metadata()?.size, 3);
+    assert!(fs.info().namemax > 0);
+    fs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn dir_entries() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let root = HostFS::new(dir.path()).root_inode();
+    let sub = root.create("sub", FileType::Dir, 0o755)?;
+    sub.create("a", FileType::File, 0o644)?;
+    assert_eq!(root.list()?, [".", "..", "sub"]);
+    assert_eq!(
+        root.lookup("sub/../sub/a")?.metadata()?.type_,
+        FileType::File
+    );
+    assert_eq!(root.find("..")?.metadata()?.inode, root.metadata()?.inode);
+    assert_eq!(root.find("sub/a").err(), Some(FsError::EntryNotFound));
+    assert_eq!(
+        root.create("x/y", FileType::File, 0o644).err(),
+        Some(FsError::InvalidParam)
+    );
+
+    root.move_("sub", &root, "dir")?;
+    let sub = root.find("dir")?;
+    sub.link("b", &sub.find("a")?)?;
+    assert_eq!(sub.find("a")?.metadata()?.nlinks, 2);
+    assert_eq!(root.unlink("dir"), Err(FsError::DirNotEmpty));
+    sub.unlink("a")?;
+    sub.unlink("b")?;
+    root.unlink("dir")?;
+    assert_eq!(root.list()?, [".", ".."]);
+    Ok(())
+}
+
+#[test]
+fn set_metadata() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let root = HostFS::new(dir.path()).root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    let mut metadata = file.metadata()?;
+    metadata.mode = 0o600;
+    metadata.mtime = Timespec { sec: 1000, nsec: 0 };
+    file.set_metadata(&metadata)?;
+    let metadata = file.metadata()?;
+    #[cfg(unix)]
+    assert_eq!(metadata.mode, 0o600);
+    assert_eq!(metadata.mtime, Timespec { sec: 1000, nsec: 0 });
+    Ok(())
+}
+
+#[test]
+fn mount_on_mountfs() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("host_file"), b"from host").unwrap();
+    let rootfs = MountFS::new(RamFS::new()) as Arc<dyn FileSystem>;
+    let root = rootfs.root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777)?;
+    mnt.downcast_ref::<MNode>()
+        .unwrap()
+        .mount(HostFS::new(dir.path()))?;
+
+    let file = root.lookup("mnt/host_file")?;
+    let mut buf = [0u8; 9];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"from host");
+    root.lookup("mnt")?
+        .create("new", FileType::File, 0o644)?
+        .write_at(0, b"to host")?;
+    assert_eq!(std::fs::read(dir.path().join("new")).unwrap(), b"to host");
+    Ok(())
+}
+
+#[test]
+fn entries_sorted() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let root = HostFS::new(dir.path()).root_inode();
+    for name in &["c", "a", "d", "b"] {
+        root.create(name, FileType::File, 0o644)?;
+    }
+    assert_eq!(root.list()?, [".", "..", "a", "b", "c", "d"]);
+    // changes show up in the next listing
+    root.unlink("b")?;
+    assert_eq!(root.list()?, [".", "..", "a", "c", "d"]);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_names() -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join(OsStr::from_bytes(b"b\xff")), b"").unwrap();
+    let root = HostFS::new(dir.path()).root_inode();
+    root.create("a", FileType::File, 0o644)?;
+    assert_eq!(root.list()?, [".", "..", "a", "b\u{fffd}"]);
+    assert_eq!(root.get_entry_bytes(3)?, b"b\xff");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_not_followed() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    std::fs::write(outside.path().join("secret"), b"secret").unwrap();
+    let link = |name: &str, target: PathBuf| {
+        std::os::unix::fs::symlink(target, dir.path().join(name)).unwrap()
+    };
+    link("dir", outside.path().to_path_buf());
+    link("file", outside.path().join("secret"));
+    let root = HostFS::new(dir.path()).root_inode();
+
+    let dir_link = root.find("dir")?;
+    assert_eq!(dir_link.metadata()?.type_, FileType::SymLink);
+    assert_eq!(dir_link.find("secret").err(), Some(FsError::NotDir));
+    assert_eq!(root.lookup("dir/secret").err(), Some(FsError::NotDir));
+    assert_eq!(
+        dir_link.create("new", FileType::File, 0o644).err(),
+        Some(FsError::NotDir)
+    );
+    assert_eq!(dir_link.get_entry(2).err(), Some(FsError::NotDir));
+    assert!(!outside.path().join("new").exists());
+
+    let file_link = root.find("file")?;
+    assert_eq!(file_link.write_at(0, b"x"), Err(FsError::NotFile));
+    assert_eq!(file_link.resize(0), Err(FsError::NotFile));
+    assert_eq!(
+        std::fs::read(outside.path().join("secret")).unwrap(),
+        b"secret"
+    );
+    Ok(())
+}
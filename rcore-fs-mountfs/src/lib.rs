@@ -8,11 +8,14 @@ use alloc::{
     collections::BTreeMap,
     string::String,
     sync::{Arc, Weak},
+    vec::Vec,
 };
 use core::any::Any;
+use rcore_fs::dev::CancelToken;
 use rcore_fs::vfs::*;
 use spin::RwLock;
 
+pub mod merge;
 #[cfg(test)]
 mod tests;
 
@@ -109,6 +112,13 @@ impl MNode {
         Ok(new_fs)
     }
 
+    /// Mount file system `upper` over this directory, keeping its content visible.
+    ///
+    /// See `merge::MergeFS` for details.
+    pub fn mount_merge(&self, upper: Arc<dyn FileSystem>) -> Result<Arc<MountFS>> {
+        self.mount(merge::MergeFS::new(upper, self.inode.clone())?)
+    }
+
     /// Get the root INode of the mounted fs at here.
     /// Return self if no mounted fs.
     fn overlaid_inode(&self) -> Arc<MNode> {
@@ -215,9 +225,29 @@ impl FileSystem for MountFS {
         self.root_inode()
     }
 
+    fn root_named(&self, name: &str) -> Result<Arc<dyn INode>> {
+        Ok(MNode {
+            inode: self.inner.root_named(name)?,
+            vfs: self.self_ref.upgrade().unwrap(),
+            self_ref: Weak::default(),
+        }
+        .wrap())
+    }
+
     fn info(&self) -> FsInfo {
         self.inner.info()
     }
+
+    fn run_maintenance(&self, budget: usize) -> Result<usize> {
+        let mut done = self.inner.run_maintenance(budget)?;
+        for mount_fs in self.mountpoints.read().values() {
+            if done >= budget {
+                break;
+            }
+            done += mount_fs.run_maintenance(budget - done)?;
+        }
+        Ok(done)
+    }
 }
 
 // unwrap `MNode` and forward methods to inner except `find()`
@@ -230,6 +260,28 @@ impl INode for MNode {
         self.inode.write_at(offset, buf)
     }
 
+    fn read_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        self.inode.read_at_cancellable(offset, buf, token)
+    }
+
+    fn write_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        self.inode.write_at_cancellable(offset, buf, token)
+    }
+
+    fn append(&self, buf: &[u8]) -> Result<(usize, usize)> {
+        self.inode.append(buf)
+    }
+
     fn poll(&self) -> Result<PollStatus> {
         self.inode.poll()
     }
@@ -287,10 +339,62 @@ impl INode for MNode {
         Ok(self.find(false, name)?)
     }
 
+    fn find_bytes(&self, name: &[u8]) -> Result<Arc<dyn INode>> {
+        if let Ok(name) = core::str::from_utf8(name) {
+            return Ok(self.find(false, name)?);
+        }
+        // not "." or "..", just going down
+        Ok(MNode {
+            inode: self.overlaid_inode().inode.find_bytes(name)?,
+            vfs: self.vfs.clone(),
+            self_ref: Weak::default(),
+        }
+        .wrap()
+        .overlaid_inode())
+    }
+
+    fn create_bytes(&self, name: &[u8], type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        Ok(MNode {
+            inode: self.inode.create_bytes(name, type_, mode)?,
+            vfs: self.vfs.clone(),
+            self_ref: Weak::default(),
+        }
+        .wrap())
+    }
+
+    fn link_bytes(&self, name: &[u8], other: &Arc<dyn INode>) -> Result<()> {
+        let other = &other
+            .downcast_ref::<Self>()
+            .ok_or(FsError::NotSameFs)?
+            .inode;
+        self.inode.link_bytes(name, other)
+    }
+
+    fn unlink_bytes(&self, name: &[u8]) -> Result<()> {
+        let inode_id = self.inode.find_bytes(name)?.metadata()?.inode;
+        // target INode is being mounted
+        if self.vfs.mountpoints.read().contains_key(&inode_id) {
+            return Err(FsError::Busy);
+        }
+        self.inode.unlink_bytes(name)
+    }
+
+    fn move_bytes(&self, old_name: &[u8], target: &Arc<dyn INode>, new_name: &[u8]) -> Result<()> {
+        let target = &target
+            .downcast_ref::<Self>()
+            .ok_or(FsError::NotSameFs)?
+            .inode;
+        self.inode.move_bytes(old_name, target, new_name)
+    }
+
     fn get_entry(&self, id: usize) -> Result<String> {
         self.inode.get_entry(id)
     }
 
+    fn get_entry_bytes(&self, id: usize) -> Result<Vec<u8>> {
+        self.inode.get_entry_bytes(id)
+    }
+
     fn get_entry_with_metadata(&self, id: usize) -> Result<(Metadata, String)> {
         self.inode.get_entry_with_metadata(id)
     }
@@ -303,6 +407,10 @@ impl INode for MNode {
         self.inode.mmap(area)
     }
 
+    fn query_extension(&self, id: ExtensionId) -> Option<&dyn Any> {
+        self.inode.query_extension(id)
+    }
+
     fn fs(&self) -> Arc<dyn FileSystem> {
         self.vfs.clone()
     }
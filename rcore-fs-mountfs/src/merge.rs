@@ -0,0 +1,470 @@
+//! A file system merging a writable upper tree over a read-only lower directory
+//!
+//! Rules:
+//! * Lookup prefers the upper tree. A directory existing in both trees is merged,
+//!   otherwise the upper entry hides the lower one.
+//! * The lower tree is never modified. Writing, resizing or setting metadata of a
+//!   lower file copies it up first, creating its parent directories in the upper tree.
+//!   The copy keeps the mode, owner and times, and is removed again if it fails.
+//! * New entries are always created in the upper tree.
+//! * Entries only in the lower tree can not be unlinked, linked or moved (`NotSupported`).
+//!   Unlinking a copied-up file reveals the lower version again.
+//! * An INode in use keeps its upper INode when it is unlinked or moved.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+use rcore_fs::dev::CancelToken;
+use rcore_fs::vfs::*;
+use spin::{Mutex, Once};
+
+/// Merge `upper` file system over `lower` directory
+pub struct MergeFS {
+    /// The writable file system
+    upper: Arc<dyn FileSystem>,
+    /// The read-only directory
+    lower: Arc<dyn INode>,
+    /// The root INode, shared while it is in use
+    root: Mutex<Weak<MergeINode>>,
+    /// Weak reference to self
+    self_ref: Weak<MergeFS>,
+}
+
+/// INode for `MergeFS`
+///
+/// Lookups return the `MergeINode` already in use for an entry if there is one,
+/// so a copy up through one handle is seen by all others.
+pub struct MergeINode {
+    /// The INode in upper FS, set once it exists
+    upper: Once<Arc<dyn INode>>,
+    /// The INode in lower directory
+    lower: Option<Arc<dyn INode>>,
+    /// Parent directory and the name in it, `None` for root
+    parent: Option<(Arc<MergeINode>, String)>,
+    /// Held while the file is copied up
+    copy_lock: Mutex<()>,
+    /// Children in use by name
+    children: Mutex<BTreeMap<String, Weak<MergeINode>>>,
+    /// Merged entry names, taken when entry 0 is read
+    entries: Mutex<Option<Vec<String>>>,
+    /// Associated `MergeFS`
+    fs: Arc<MergeFS>,
+    /// Weak reference to self
+    self_ref: Weak<MergeINode>,
+}
+
+impl MergeFS {
+    /// Create a `MergeFS` with writes going to `upper`, and reads falling through to `lower`
+    pub fn new(upper: Arc<dyn FileSystem>, lower: Arc<dyn INode>) -> Result<Arc<Self>> {
+        if lower.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let fs = Arc::new(MergeFS {
+            upper,
+            lower,
+            root: Mutex::new(Weak::default()),
+            self_ref: Weak::default(),
+        });
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ref = weak;
+            Ok(Arc::from_raw(ptr))
+        }
+    }
+
+    /// Strong type version of `root_inode`
+    pub fn root_inode(&self) -> Arc<MergeINode> {
+        let mut root = self.root.lock();
+        if let Some(inode) = root.upgrade() {
+            return inode;
+        }
+        let inode = MergeINode::new(
+            self.self_ref.upgrade().unwrap(),
+            None,
+            Some(self.upper.root_inode()),
+            Some(self.lower.clone()),
+        );
+        *root = Arc::downgrade(&inode);
+        inode
+    }
+}
+
+impl FileSystem for MergeFS {
+    fn sync(&self) -> Result<()> {
+        self.upper.sync()
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.root_inode()
+    }
+
+    fn info(&self) -> FsInfo {
+        self.upper.info()
+    }
+
+    fn run_maintenance(&self, budget: usize) -> Result<usize> {
+        self.upper.run_maintenance(budget)
+    }
+}
+
+impl MergeINode {
+    /// Create a `MergeINode` wrapped with `Arc<..>`
+    fn new(
+        fs: Arc<MergeFS>,
+        parent: Option<(Arc<MergeINode>, String)>,
+        upper: Option<Arc<dyn INode>>,
+        lower: Option<Arc<dyn INode>>,
+    ) -> Arc<Self> {
+        let inode = MergeINode {
+            upper: Once::new(),
+            lower,
+            parent,
+            copy_lock: Mutex::new(()),
+            children: Mutex::new(BTreeMap::new()),
+            entries: Mutex::new(None),
+            fs,
+            self_ref: Weak::default(),
+        };
+        if let Some(upper) = upper {
+            inode.upper.call_once(|| upper);
+        }
+        inode.wrap()
+    }
+
+    /// Wrap pure `MergeINode` with `Arc<..>`.
+    /// Used in constructors.
+    fn wrap(self) -> Arc<Self> {
+        let inode = Arc::new(self);
+        let weak = Arc::downgrade(&inode);
+        let ptr = Arc::into_raw(inode) as *mut Self;
+        unsafe {
+            (*ptr).self_ref = weak;
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// The INode in upper FS, `None` if it is not there (yet)
+    fn upper(&self) -> Option<Arc<dyn INode>> {
+        self.upper.r#try().cloned()
+    }
+
+    /// The INode to read from: upper if exists, otherwise lower
+    fn effective(&self) -> Result<Arc<dyn INode>> {
+        match self.upper() {
+            Some(upper) => Ok(upper),
+            None => self.lower.clone().ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    /// Get the upper INode, copy it up from lower if not exists
+    fn copy_up(&self) -> Result<Arc<dyn INode>> {
+        if let Some(upper) = self.upper() {
+            return Ok(upper);
+        }
+        // only the root has no parent, and it always has an upper INode
+        let (parent, name) = self.parent.as_ref().unwrap();
+        let parent = parent.copy_up()?;
+        let _lock = self.copy_lock.lock();
+        if let Some(upper) = self.upper() {
+            return Ok(upper);
+        }
+        let lower = self.lower.as_ref().ok_or(FsError::EntryNotFound)?;
+        let info = lower.metadata()?;
+        let inode = match parent.create(name, info.type_, info.mode as u32) {
+            Ok(inode) => inode,
+            // created in upper FS behind our back, its data is newer than lower
+            Err(FsError::EntryExist) => parent.find(name)?,
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = Self::copy_data(lower, &inode, &info) {
+            // do not let a partial copy hide lower
+            parent.unlink(name).ok();
+            return Err(e);
+        }
+        Ok(self.upper.call_once(|| inode).clone())
+    }
+
+    /// Copy the content, mode, owner and times of `lower` to `upper`
+    fn copy_data(lower: &Arc<dyn INode>, upper: &Arc<dyn INode>, info: &Metadata) -> Result<()> {
+        if info.type_ != FileType::Dir {
+            let mut buf = vec![0u8; 0x1000];
+            let mut offset = 0;
+            loop {
+                let len = lower.read_at(offset, &mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                upper.write_at(offset, &buf[..len])?;
+                offset += len;
+            }
+        }
+        match upper.set_metadata(info) {
+            Ok(()) | Err(FsError::NotSupported) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Names of all entries, upper first
+    fn entries(&self) -> Result<Vec<String>> {
+        let mut names = match self.upper() {
+            Some(upper) => upper.list()?,
+            None => Vec::new(),
+        };
+        if let Some(lower) = &self.lower {
+            let upper: BTreeSet<String> = names.iter().cloned().collect();
+            names.extend(
+                lower
+                    .list()?
+                    .into_iter()
+                    .filter(|name| !upper.contains(name)),
+            );
+        }
+        Ok(names)
+    }
+
+    /// Strong type version of `find()`
+    pub fn find(&self, name: &str) -> Result<Arc<Self>> {
+        if self.effective()?.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        match name {
+            "" | "." => return Ok(self.self_ref.upgrade().unwrap()),
+            ".." => {
+                return Ok(match &self.parent {
+                    Some((parent, _)) => parent.clone(),
+                    None => self.self_ref.upgrade().unwrap(),
+                })
+            }
+            _ => {}
+        }
+        let mut children = self.children.lock();
+        if let Some(child) = children.get(name).and_then(Weak::upgrade) {
+            return Ok(child);
+        }
+        let upper = match self.upper() {
+            Some(upper) => match upper.find(name) {
+                Ok(inode) => Some(inode),
+                Err(FsError::EntryNotFound) => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+        let lower = match &self.lower {
+            Some(lower) => match lower.find(name) {
+                Ok(inode) => Some(inode),
+                Err(FsError::EntryNotFound) => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+        let lower = match (&upper, lower) {
+            (None, None) => return Err(FsError::EntryNotFound),
+            // merge directories only, otherwise upper hides lower
+            (Some(upper), Some(lower)) => {
+                let is_dir = |inode: &Arc<dyn INode>| -> Result<bool> {
+                    Ok(inode.metadata()?.type_ == FileType::Dir)
+                };
+                if is_dir(upper)? && is_dir(&lower)? {
+                    Some(lower)
+                } else {
+                    None
+                }
+            }
+            (_, lower) => lower,
+        };
+        let child = MergeINode::new(
+            self.fs.clone(),
+            Some((self.self_ref.upgrade().unwrap(), String::from(name))),
+            upper,
+            lower,
+        );
+        children.insert(String::from(name), Arc::downgrade(&child));
+        Ok(child)
+    }
+
+    /// Strong type version of `create()`
+    pub fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<Self>> {
+        match self.find(name) {
+            Ok(_) => return Err(FsError::EntryExist),
+            Err(FsError::EntryNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let inode = self.copy_up()?.create(name, type_, mode)?;
+        let child = MergeINode::new(
+            self.fs.clone(),
+            Some((self.self_ref.upgrade().unwrap(), String::from(name))),
+            Some(inode),
+            None,
+        );
+        self.children
+            .lock()
+            .insert(String::from(name), Arc::downgrade(&child));
+        Ok(child)
+    }
+
+    /// Get the upper INode of entry `name`, fail if it only exists in lower
+    fn upper_entry(&self, name: &str) -> Result<Arc<dyn INode>> {
+        let upper = self.upper().ok_or(FsError::NotSupported)?;
+        match upper.find(name) {
+            Err(FsError::EntryNotFound) if self.find(name).is_ok() => Err(FsError::NotSupported),
+            result => result,
+        }
+    }
+}
+
+impl INode for MergeINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.effective()?.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.copy_up()?.write_at(offset, buf)
+    }
+
+    fn read_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        self.effective()?.read_at_cancellable(offset, buf, token)
+    }
+
+    fn write_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        self.copy_up()?.write_at_cancellable(offset, buf, token)
+    }
+
+    fn append(&self, buf: &[u8]) -> Result<(usize, usize)> {
+        self.copy_up()?.append(buf)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        self.effective()?.poll()
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        self.effective()?.metadata()
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        self.copy_up()?.set_metadata(metadata)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        match self.upper() {
+            Some(upper) => upper.sync_all(),
+            None => Ok(()),
+        }
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        match self.upper() {
+            Some(upper) => upper.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        self.copy_up()?.resize(len)
+    }
+
+    fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        Ok(self.create(name, type_, mode)?)
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        let other = other.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        let other = other.upper().ok_or(FsError::NotSupported)?;
+        if self.find(name).is_ok() {
+            return Err(FsError::EntryExist);
+        }
+        self.copy_up()?.link(name, &other)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.upper_entry(name)?;
+        let upper = self.upper().ok_or(FsError::EntryNotFound)?;
+        upper.unlink(name)?;
+        // an INode in use keeps its upper INode, later lookups see lower again
+        self.children.lock().remove(name);
+        Ok(())
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        let target = target.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        self.upper_entry(old_name)?;
+        if target.find(new_name).is_ok() {
+            return Err(FsError::EntryExist);
+        }
+        let upper = self.upper().ok_or(FsError::EntryNotFound)?;
+        upper.move_(old_name, &target.copy_up()?, new_name)?;
+        self.children.lock().remove(old_name);
+        target.children.lock().remove(new_name);
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        Ok(self.find(name)?)
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        if self.effective()?.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let mut entries = self.entries.lock();
+        if id == 0 || entries.is_none() {
+            *entries = Some(self.entries()?);
+        }
+        entries
+            .as_ref()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.effective()?.io_control(cmd, data)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    fn query_extension(&self, id: ExtensionId) -> Option<&dyn Any> {
+        match self.upper.r#try() {
+            Some(upper) => upper.query_extension(id),
+            None => self.lower.as_ref()?.query_extension(id),
+        }
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for MergeINode {
+    /// Forget the entry in parent, unless it has been replaced
+    fn drop(&mut self) {
+        if let Some((parent, name)) = &self.parent {
+            let mut children = parent.children.lock();
+            if children
+                .get(name)
+                .map_or(false, |child| child.ptr_eq(&self.self_ref))
+            {
+                children.remove(name);
+            }
+        }
+    }
+}
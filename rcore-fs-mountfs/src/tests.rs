@@ -25,3 +25,118 @@ fn remove_busy() {
     mnt.downcast_ref::<MNode>().unwrap().mount(ramfs).unwrap();
     assert_eq!(root.unlink("mnt"), Err(FsError::Busy));
 }
+
+fn merge_setup() -> (Arc<dyn INode>, Arc<dyn INode>, Arc<dyn INode>) {
+    let rootfs = MountFS::new(RamFS::new()) as Arc<dyn FileSystem>;
+    let root = rootfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777).unwrap();
+    let file = dir.create("file", FileType::File, 0o666).unwrap();
+    file.write_at(0, b"lower").unwrap();
+    let sub = dir.create("sub", FileType::Dir, 0o777).unwrap();
+    sub.create("deep", FileType::File, 0o666)
+        .unwrap()
+        .write_at(0, b"deep")
+        .unwrap();
+
+    let upper = RamFS::new();
+    let upper_root = upper.root_inode();
+    dir.downcast_ref::<MNode>()
+        .unwrap()
+        .mount_merge(upper)
+        .unwrap();
+    (root, file, upper_root)
+}
+
+fn read_all(inode: &Arc<dyn INode>) -> Vec<u8> {
+    let mut buf = vec![0u8; inode.metadata().unwrap().size];
+    inode.read_at(0, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn merge_read_through() {
+    let (root, _, upper) = merge_setup();
+    let file = root.lookup("dir/file").unwrap();
+    assert_eq!(read_all(&file), b"lower");
+    assert_eq!(read_all(&root.lookup("dir/sub/deep").unwrap()), b"deep");
+    assert_eq!(upper.find("file").err(), Some(FsError::EntryNotFound));
+}
+
+#[test]
+fn merge_copy_up() {
+    let (root, lower, upper) = merge_setup();
+    let file = root.lookup("dir/file").unwrap();
+    file.write_at(0, b"UP").unwrap();
+    assert_eq!(read_all(&file), b"UPwer");
+    assert_eq!(read_all(&lower), b"lower");
+    assert_eq!(read_all(&upper.find("file").unwrap()), b"UPwer");
+
+    let deep = root.lookup("dir/sub/deep").unwrap();
+    deep.resize(2).unwrap();
+    assert_eq!(read_all(&upper.lookup("sub/deep").unwrap()), b"de");
+    assert_eq!(read_all(&root.lookup("dir/sub/deep").unwrap()), b"de");
+}
+
+#[test]
+fn merge_create_and_list() {
+    let (root, _, upper) = merge_setup();
+    let dir = root.lookup("dir").unwrap();
+    assert_eq!(
+        dir.create("file", FileType::File, 0o666).err(),
+        Some(FsError::EntryExist)
+    );
+    dir.create("new", FileType::File, 0o666).unwrap();
+    assert!(upper.find("new").is_ok());
+    root.lookup("dir/file").unwrap().write_at(0, b"x").unwrap();
+
+    let mut names = dir.list().unwrap();
+    names.sort();
+    assert_eq!(names, vec![".", "..", "file", "new", "sub"]);
+}
+
+#[test]
+fn merge_unlink() {
+    let (root, _, _) = merge_setup();
+    let dir = root.lookup("dir").unwrap();
+    assert_eq!(dir.unlink("file"), Err(FsError::NotSupported));
+    dir.find("file").unwrap().write_at(0, b"UP").unwrap();
+    dir.unlink("file").unwrap();
+    assert_eq!(read_all(&dir.find("file").unwrap()), b"lower");
+
+    dir.create("new", FileType::File, 0o666).unwrap();
+    dir.unlink("new").unwrap();
+    assert_eq!(dir.find("new").err(), Some(FsError::EntryNotFound));
+}
+
+#[test]
+fn merge_copy_up_two_handles() {
+    let (root, lower, upper) = merge_setup();
+    let a = root.lookup("dir/file").unwrap();
+    let b = root.lookup("dir/file").unwrap();
+    a.write_at(0, b"AAAAA").unwrap();
+    // b sees the copy made through a
+    assert_eq!(read_all(&b), b"AAAAA");
+    b.write_at(5, b"!").unwrap();
+    assert_eq!(read_all(&a), b"AAAAA!");
+    assert_eq!(read_all(&upper.find("file").unwrap()), b"AAAAA!");
+    assert_eq!(read_all(&lower), b"lower");
+}
+
+#[test]
+fn merge_open_handle_keeps_upper() {
+    let (root, lower, upper) = merge_setup();
+    let dir = root.lookup("dir").unwrap();
+    let file = dir.find("file").unwrap();
+    let mut info = lower.metadata().unwrap();
+    info.uid = 7;
+    info.mtime = Timespec { sec: 1234, nsec: 0 };
+    lower.set_metadata(&info).unwrap();
+
+    file.write_at(0, b"UP").unwrap();
+    let copied = upper.find("file").unwrap().metadata().unwrap();
+    assert_eq!((copied.uid, copied.mode), (7, info.mode));
+    dir.unlink("file").unwrap();
+    // the open handle still sees its copy, a new lookup sees lower
+    assert_eq!(read_all(&file), b"UPwer");
+    assert_eq!(read_all(&dir.find("file").unwrap()), b"lower");
+}
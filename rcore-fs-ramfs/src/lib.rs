@@ -121,6 +121,16 @@ impl INode for LockedINode {
         Ok(buf.len())
     }
 
+    fn append(&self, buf: &[u8]) -> Result<(usize, usize)> {
+        let mut file = self.0.write();
+        if file.extra.type_ == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        let offset = file.content.len();
+        file.content.extend_from_slice(buf);
+        Ok((offset, buf.len()))
+    }
+
     fn poll(&self) -> Result<PollStatus> {
         let file = self.0.read();
         if file.extra.type_ == FileType::Dir {
@@ -0,0 +1,121 @@
+//! Tamper-evident audit log on an append-only file
+//!
+//! Each record is stored as `len: u32 | data | mac`, where `mac` is computed
+//! over the previous record's mac and `len | data`. Altering or removing any
+//! record breaks the chain after it. SEFS holds no secret key, so the MAC is
+//! provided by the integration, e.g. an HMAC keyed by the enclave sealing key.
+//!
+//! The MAC of the last record is also kept in the inode, next to the size, so
+//! cutting records off the tail, or swapping in another log, is detected too.
+//! This holds as long as the meta file can not be rolled back by the host, as
+//! the inode is not covered by the chain.
+
+use alloc::{sync::Arc, vec};
+use rcore_fs::vfs::{FsError, INode, Result};
+use spin::Mutex;
+
+use crate::{INodeImpl, INODE_FLAG_APPEND};
+
+/// Size of a MAC in bytes
+pub const MAC_SIZE: usize = 32;
+
+/// Keyed MAC chaining the records
+pub trait AuditMac: Send + Sync {
+    /// Compute the MAC of `record` following the MAC `prev`
+    fn mac(&self, prev: &[u8; MAC_SIZE], record: &[u8]) -> [u8; MAC_SIZE];
+}
+
+/// An audit log stored in a file
+pub struct AuditLog {
+    inode: Arc<dyn INode>,
+    mac: &'static dyn AuditMac,
+    /// End offset and MAC of the last record
+    head: Mutex<(usize, [u8; MAC_SIZE])>,
+}
+
+impl AuditLog {
+    /// Open the audit log in `inode`, verify its records and make the file append-only.
+    ///
+    /// `inode` must be a File of SEFS.
+    pub fn open(inode: Arc<dyn INode>, mac: &'static dyn AuditMac) -> Result<Self> {
+        let inode_impl = inode
+            .downcast_ref::<INodeImpl>()
+            .ok_or(FsError::NotSupported)?;
+        inode_impl.set_flags(inode_impl.flags() | INODE_FLAG_APPEND)?;
+        let log = AuditLog {
+            inode,
+            mac,
+            head: Mutex::new((0, [0; MAC_SIZE])),
+        };
+        *log.head.lock() = log.walk()?;
+        Ok(log)
+    }
+
+    /// Append a record
+    pub fn append(&self, data: &[u8]) -> Result<()> {
+        let mut head = self.head.lock();
+        let mut record = vec![0u8; 4 + data.len() + MAC_SIZE];
+        record[..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        record[4..4 + data.len()].copy_from_slice(data);
+        let mac = self.mac.mac(&head.1, &record[..4 + data.len()]);
+        record[4 + data.len()..].copy_from_slice(&mac);
+        match self.inode.write_at(head.0, &record) {
+            Ok(len) if len == record.len() => {}
+            result => {
+                // a partial record would break the chain, cut it off
+                self.inode_impl().resize_data(head.0).ok();
+                return Err(result.err().unwrap_or(FsError::DeviceError));
+            }
+        }
+        self.inode_impl().disk_inode.write().audit_head = mac;
+        *head = (head.0 + record.len(), mac);
+        Ok(())
+    }
+
+    /// Verify all records, return the MAC of the last one.
+    ///
+    /// Return `Corrupted` if the chain is broken or does not end at the head
+    /// kept in the inode.
+    pub fn verify_chain(&self) -> Result<[u8; MAC_SIZE]> {
+        let _head = self.head.lock();
+        Ok(self.walk()?.1)
+    }
+
+    fn inode_impl(&self) -> &INodeImpl {
+        self.inode.downcast_ref::<INodeImpl>().unwrap()
+    }
+
+    /// Walk through the records, return the end offset and MAC of the last one
+    fn walk(&self) -> Result<(usize, [u8; MAC_SIZE])> {
+        let size = self.inode.metadata()?.size;
+        let mut offset = 0;
+        let mut prev = [0u8; MAC_SIZE];
+        while offset < size {
+            let mut len = [0u8; 4];
+            self.read_exact(offset, &mut len)?;
+            let len = u32::from_le_bytes(len) as usize;
+            if offset + 4 + len + MAC_SIZE > size {
+                return Err(FsError::Corrupted);
+            }
+            let mut record = vec![0u8; 4 + len + MAC_SIZE];
+            self.read_exact(offset, &mut record)?;
+            let mac = self.mac.mac(&prev, &record[..4 + len]);
+            if mac[..] != record[4 + len..] {
+                return Err(FsError::Corrupted);
+            }
+            offset += record.len();
+            prev = mac;
+        }
+        if prev != self.inode_impl().disk_inode.read().audit_head {
+            return Err(FsError::Corrupted);
+        }
+        Ok((offset, prev))
+    }
+
+    fn read_exact(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        match self.inode.read_at(offset, buf)? {
+            len if len == buf.len() => Ok(()),
+            _ => Err(FsError::Corrupted),
+        }
+    }
+}
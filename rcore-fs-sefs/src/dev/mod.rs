@@ -1,10 +1,14 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, format, vec::Vec};
+use core::ops::Deref;
 
+use rcore_fs::dev::{check_cancel, CancelToken, DevError};
 use rcore_fs::vfs::FsError;
 
+pub use self::static_impl::*;
 #[cfg(any(test, feature = "std"))]
 pub use self::std_impl::*;
 
+mod static_impl;
 pub mod std_impl;
 
 /// A file stores a normal file or directory.
@@ -21,7 +25,7 @@ pub trait File: Send + Sync {
         if len == buf.len() {
             Ok(())
         } else {
-            Err(DeviceError)
+            Err(DeviceError::IOError)
         }
     }
     fn write_all_at(&self, buf: &[u8], offset: usize) -> DevResult<()> {
@@ -29,9 +33,30 @@ pub trait File: Send + Sync {
         if len == buf.len() {
             Ok(())
         } else {
-            Err(DeviceError)
+            Err(DeviceError::IOError)
         }
     }
+
+    /// Same as `read_at`, but give up when `token` is cancelled
+    fn read_at_cancellable(
+        &self,
+        buf: &mut [u8],
+        offset: usize,
+        token: Option<&dyn CancelToken>,
+    ) -> DevResult<usize> {
+        check_cancel(token)?;
+        self.read_at(buf, offset)
+    }
+    /// Same as `write_at`, but give up when `token` is cancelled
+    fn write_at_cancellable(
+        &self,
+        buf: &[u8],
+        offset: usize,
+        token: Option<&dyn CancelToken>,
+    ) -> DevResult<usize> {
+        check_cancel(token)?;
+        self.write_at(buf, offset)
+    }
 }
 
 /// The collection of all files in the FS.
@@ -39,15 +64,71 @@ pub trait Storage: Send + Sync {
     fn open(&self, file_id: usize) -> DevResult<Box<dyn File>>;
     fn create(&self, file_id: usize) -> DevResult<Box<dyn File>>;
     fn remove(&self, file_id: usize) -> DevResult<()>;
+    /// List the ids of all files in the storage
+    fn list(&self) -> DevResult<Vec<usize>>;
+    /// Get the size in bytes of file `file_id`
+    fn stat(&self, file_id: usize) -> DevResult<usize>;
+    /// Whether files can never be created, written or removed
+    fn is_read_only(&self) -> bool {
+        false
+    }
+    /// Same as `open`, but borrow the file if it lives as long as the program
+    fn open_ref(&self, file_id: usize) -> DevResult<FileRef> {
+        Ok(FileRef::Boxed(self.open(file_id)?))
+    }
+}
+
+/// A file opened from a `Storage`, borrowed from static memory if possible
+pub enum FileRef {
+    Boxed(Box<dyn File>),
+    Static(&'static dyn File),
+}
+
+impl Deref for FileRef {
+    type Target = dyn File;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            FileRef::Boxed(file) => file.as_ref(),
+            FileRef::Static(file) => *file,
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct DeviceError;
+pub enum DeviceError {
+    /// The device failed to complete the operation
+    IOError,
+    /// The operation was cancelled or its deadline has passed
+    TimedOut,
+}
 
 pub type DevResult<T> = Result<T, DeviceError>;
 
+/// Parse the name of a file in a storage directory, which is its id.
+///
+/// Other names are `None`, including those parsed to an id but not written
+/// for it, e.g. "007" or "+7", so they are never taken for the file "7".
+pub fn parse_file_id(name: &str) -> Option<usize> {
+    name.parse()
+        .ok()
+        .filter(|id: &usize| format!("{}", id) == name)
+}
+
 impl From<DeviceError> for FsError {
-    fn from(_: DeviceError) -> Self {
-        FsError::DeviceError
+    fn from(e: DeviceError) -> Self {
+        match e {
+            DeviceError::IOError => FsError::DeviceError,
+            DeviceError::TimedOut => FsError::TimedOut,
+        }
+    }
+}
+
+impl From<DevError> for DeviceError {
+    fn from(e: DevError) -> Self {
+        match e {
+            DevError::IOError => DeviceError::IOError,
+            DevError::TimedOut => DeviceError::TimedOut,
+        }
     }
 }
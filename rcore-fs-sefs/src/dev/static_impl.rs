@@ -0,0 +1,77 @@
+use super::{DevResult, DeviceError, FileRef};
+use alloc::{boxed::Box, vec::Vec};
+
+/// Read-only storage on files in memory, e.g. linked into the kernel.
+///
+/// Files are given as `(file_id, content)` pairs. They are opened without
+/// allocating. Writes fail with `IOError`, SEFS on it rejects changes with
+/// `ReadOnlyFs` before trying them.
+pub struct StaticStorage {
+    files: &'static [(usize, &'static [u8])],
+}
+
+impl StaticStorage {
+    pub fn new(files: &'static [(usize, &'static [u8])]) -> Self {
+        StaticStorage { files }
+    }
+
+    fn get(&self, file_id: usize) -> DevResult<&'static &'static [u8]> {
+        let files: &'static [(usize, &'static [u8])] = self.files;
+        files
+            .iter()
+            .find(|(id, _)| *id == file_id)
+            .map(|(_, data)| data)
+            .ok_or(DeviceError::IOError)
+    }
+}
+
+impl super::Storage for StaticStorage {
+    fn open(&self, file_id: usize) -> DevResult<Box<dyn super::File>> {
+        Ok(Box::new(*self.get(file_id)?))
+    }
+
+    fn create(&self, _file_id: usize) -> DevResult<Box<dyn super::File>> {
+        Err(DeviceError::IOError)
+    }
+
+    fn remove(&self, _file_id: usize) -> DevResult<()> {
+        Err(DeviceError::IOError)
+    }
+
+    fn list(&self) -> DevResult<Vec<usize>> {
+        Ok(self.files.iter().map(|(id, _)| *id).collect())
+    }
+
+    fn stat(&self, file_id: usize) -> DevResult<usize> {
+        Ok(self.get(file_id)?.len())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn open_ref(&self, file_id: usize) -> DevResult<FileRef> {
+        Ok(FileRef::Static(self.get(file_id)?))
+    }
+}
+
+impl super::File for &'static [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        let begin = offset.min(self.len());
+        let len = buf.len().min(self.len() - begin);
+        buf[..len].copy_from_slice(&self[begin..begin + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: usize) -> DevResult<usize> {
+        Err(DeviceError::IOError)
+    }
+
+    fn set_len(&self, _len: usize) -> DevResult<()> {
+        Err(DeviceError::IOError)
+    }
+
+    fn flush(&self) -> DevResult<()> {
+        Ok(())
+    }
+}
@@ -1,20 +1,61 @@
 #![cfg(any(test, feature = "std"))]
 
-use super::{DevResult, DeviceError};
-use spin::Mutex;
-use std::fs::{remove_file, File, OpenOptions};
+use super::{parse_file_id, DevResult, DeviceError};
+use rcore_fs::dev::{check_cancel, CancelToken};
+use spin::{Mutex, MutexGuard};
+use std::fs::{metadata, read_dir, remove_file, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct StdStorage {
     path: PathBuf,
+    /// Leave holes for zero blocks written beyond the data of files
+    sparse: bool,
 }
 
+/// Size of blocks checked for zeros in sparse files
+const SPARSE_BLOCK_SIZE: usize = 0x1000;
+
 impl StdStorage {
     pub fn new(path: impl AsRef<Path>) -> Self {
         assert!(path.as_ref().is_dir());
         StdStorage {
             path: path.as_ref().to_path_buf(),
+            sparse: false,
+        }
+    }
+
+    /// Create a storage keeping files sparse on the host.
+    ///
+    /// Zero blocks written beyond the last data of a file are not written,
+    /// leaving holes. This includes the space added by growing the file.
+    /// On host file systems without sparse files, the holes are filled with
+    /// zeros by the host, which is still correct but saves no space.
+    pub fn new_sparse(path: impl AsRef<Path>) -> Self {
+        StdStorage {
+            sparse: true,
+            ..StdStorage::new(path)
+        }
+    }
+
+    /// Get the space in bytes file `file_id` takes on the host disk
+    pub fn physical_size(&self, file_id: usize) -> DevResult<usize> {
+        let mut path = self.path.to_path_buf();
+        path.push(format!("{}", file_id));
+        let metadata = metadata(path)?;
+        #[cfg(unix)]
+        return Ok(metadata.blocks() as usize * 512);
+        #[cfg(not(unix))]
+        return Ok(metadata.len() as usize);
+    }
+
+    fn wrap(&self, file: File) -> Box<dyn super::File> {
+        match self.sparse {
+            true => Box::new(SparseFile::new(file)),
+            false => Box::new(Mutex::new(file)),
         }
     }
 }
@@ -24,7 +65,7 @@ impl super::Storage for StdStorage {
         let mut path = self.path.to_path_buf();
         path.push(format!("{}", file_id));
         let file = OpenOptions::new().read(true).write(true).open(path)?;
-        Ok(Box::new(Mutex::new(file)))
+        Ok(self.wrap(file))
     }
 
     fn create(&self, file_id: usize) -> DevResult<Box<dyn super::File>> {
@@ -35,7 +76,7 @@ impl super::Storage for StdStorage {
             .write(true)
             .create(true)
             .open(path)?;
-        Ok(Box::new(Mutex::new(file)))
+        Ok(self.wrap(file))
     }
 
     fn remove(&self, file_id: usize) -> DevResult<()> {
@@ -44,35 +85,42 @@ impl super::Storage for StdStorage {
         remove_file(path)?;
         Ok(())
     }
+
+    fn list(&self) -> DevResult<Vec<usize>> {
+        let mut ids = Vec::new();
+        for entry in read_dir(&self.path)? {
+            // skip files not created by us
+            if let Some(id) = entry?.file_name().to_str().and_then(parse_file_id) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn stat(&self, file_id: usize) -> DevResult<usize> {
+        let mut path = self.path.to_path_buf();
+        path.push(format!("{}", file_id));
+        let metadata = metadata(path)?;
+        Ok(metadata.len() as usize)
+    }
 }
 
 impl From<std::io::Error> for DeviceError {
     fn from(e: std::io::Error) -> Self {
-        panic!("{:?}", e);
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => DeviceError::TimedOut,
+            _ => DeviceError::IOError,
+        }
     }
 }
 
 impl super::File for Mutex<File> {
     fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
-        let mut file = self.lock();
-        let offset = offset as u64;
-        let real_offset = file.seek(SeekFrom::Start(offset))?;
-        if real_offset != offset {
-            return Err(DeviceError);
-        }
-        let len = file.read(buf)?;
-        Ok(len)
+        self.read_at_cancellable(buf, offset, None)
     }
 
     fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
-        let mut file = self.lock();
-        let offset = offset as u64;
-        let real_offset = file.seek(SeekFrom::Start(offset))?;
-        if real_offset != offset {
-            return Err(DeviceError);
-        }
-        let len = file.write(buf)?;
-        Ok(len)
+        self.write_at_cancellable(buf, offset, None)
     }
 
     fn set_len(&self, len: usize) -> DevResult<()> {
@@ -86,4 +134,131 @@ impl super::File for Mutex<File> {
         file.sync_all()?;
         Ok(())
     }
+
+    fn read_at_cancellable(
+        &self,
+        buf: &mut [u8],
+        offset: usize,
+        token: Option<&dyn CancelToken>,
+    ) -> DevResult<usize> {
+        let mut file = lock_cancellable(self, token)?;
+        seek(&mut file, offset)?;
+        let len = file.read(buf)?;
+        Ok(len)
+    }
+
+    fn write_at_cancellable(
+        &self,
+        buf: &[u8],
+        offset: usize,
+        token: Option<&dyn CancelToken>,
+    ) -> DevResult<usize> {
+        let mut file = lock_cancellable(self, token)?;
+        seek(&mut file, offset)?;
+        let len = file.write(buf)?;
+        Ok(len)
+    }
+}
+
+/// Lock `file`, giving up if `token` is cancelled before or after waiting
+fn lock_cancellable<'a>(
+    file: &'a Mutex<File>,
+    token: Option<&dyn CancelToken>,
+) -> DevResult<MutexGuard<'a, File>> {
+    check_cancel(token)?;
+    let file = file.lock();
+    // waiting for the lock may take a while
+    check_cancel(token)?;
+    Ok(file)
+}
+
+fn seek(file: &mut File, offset: usize) -> DevResult<()> {
+    let offset = offset as u64;
+    let real_offset = file.seek(SeekFrom::Start(offset))?;
+    if real_offset != offset {
+        return Err(DeviceError::IOError);
+    }
+    Ok(())
+}
+
+/// A host file leaving holes for zero blocks written beyond its data
+struct SparseFile {
+    file: Mutex<File>,
+    /// End of the data written to the file, the rest is a hole.
+    /// Only changed with `file` locked.
+    data_end: AtomicUsize,
+}
+
+impl SparseFile {
+    fn new(file: File) -> Self {
+        // the whole file may be data when opened
+        let data_end = file
+            .metadata()
+            .map_or(usize::max_value(), |m| m.len() as usize);
+        SparseFile {
+            file: Mutex::new(file),
+            data_end: AtomicUsize::new(data_end),
+        }
+    }
+}
+
+impl super::File for SparseFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        self.write_at_cancellable(buf, offset, None)
+    }
+
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        let file = self.file.lock();
+        file.set_len(len as u64)?;
+        // growing leaves a hole, shrinking may cut the data
+        if len < self.data_end.load(Ordering::Relaxed) {
+            self.data_end.store(len, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> DevResult<()> {
+        self.file.flush()
+    }
+
+    fn read_at_cancellable(
+        &self,
+        buf: &mut [u8],
+        offset: usize,
+        token: Option<&dyn CancelToken>,
+    ) -> DevResult<usize> {
+        self.file.read_at_cancellable(buf, offset, token)
+    }
+
+    fn write_at_cancellable(
+        &self,
+        buf: &[u8],
+        offset: usize,
+        token: Option<&dyn CancelToken>,
+    ) -> DevResult<usize> {
+        let mut file = lock_cancellable(&self.file, token)?;
+        let mut data_end = self.data_end.load(Ordering::Relaxed);
+        let end = offset + buf.len();
+        let mut begin = offset;
+        while begin < end {
+            let block_end = end.min((begin / SPARSE_BLOCK_SIZE + 1) * SPARSE_BLOCK_SIZE);
+            let block = &buf[begin - offset..block_end - offset];
+            if begin < data_end || block.iter().any(|&b| b != 0) {
+                file.seek(SeekFrom::Start(begin as u64))?;
+                file.write_all(block)?;
+                data_end = data_end.max(block_end);
+            }
+            begin = block_end;
+        }
+        self.data_end.store(data_end, Ordering::Relaxed);
+        // the skipped blocks at the end
+        if file.metadata()?.len() < end as u64 {
+            file.set_len(end as u64)?;
+        }
+        Ok(buf.len())
+    }
 }
@@ -1,6 +1,8 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 extern crate alloc;
+#[macro_use]
+extern crate log;
 
 use alloc::{
     boxed::Box,
@@ -12,18 +14,28 @@ use alloc::{
 use core::any::Any;
 use core::fmt::{Debug, Error, Formatter};
 use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use bitvec::prelude::*;
-use rcore_fs::dev::TimeProvider;
+use rcore_fs::dev::{CancelToken, RngProvider, TimeProvider};
 use rcore_fs::dirty::Dirty;
-use rcore_fs::vfs::{self, FileSystem, FsError, INode, MMapArea, Timespec};
-use spin::RwLock;
+use rcore_fs::maintenance::{self, MaintenanceTask};
+use rcore_fs::vfs::{self, ExtensionId, FileSystem, FsError, INode, MMapArea, Timespec};
+use spin::{Mutex, RwLock};
 
 use self::dev::*;
+use self::seal::{Sealer, SEAL_BLOCK_SIZE, SEAL_TAG_SIZE};
 use self::structs::*;
+pub use self::structs::{
+    DiskINode, FileType, SuperBlock, INODE_FLAG_APPEND, INODE_FLAG_IMMUTABLE, INODE_FLAG_SEALED,
+};
 
+pub mod audit;
 pub mod dev;
+pub mod seal;
 mod structs;
+#[cfg(test)]
+mod tests;
 
 /// Helper methods for `File`
 impl dyn File {
@@ -51,14 +63,76 @@ impl dyn File {
     }
 }
 
+/// Extension of INode: id of the backing file in `Storage`, as `usize`
+pub const EXT_FILE_ID: ExtensionId = ExtensionId("sefs.file_id");
+/// Extension of INode: the file system, as `Arc<SEFS>`
+pub const EXT_FS: ExtensionId = ExtensionId("sefs.fs");
+/// Extension of INode: the INode itself, as `INodeImpl`
+pub const EXT_INODE: ExtensionId = ExtensionId("sefs.inode");
+
+/// Number of inode ids a directory takes from the free map at once
+const INODE_POOL_BATCH: usize = 16;
+
+/// How strictly SEFS follows POSIX, see `SEFS::set_strictness()`
+///
+/// | Behavior                                        | `Relaxed`            | `Posix`                                        |
+/// |-------------------------------------------------|----------------------|------------------------------------------------|
+/// | `move_` onto an existing entry                  | `EntryExist`         | replace it, or `IsDir`, `NotDir`, `DirNotEmpty` |
+/// | `move_` between hard links of the same file     | `EntryExist`         | do nothing                                     |
+/// | `move_` a directory into its own subdirectory   | not checked          | `InvalidParam`, costs a walk up to the root    |
+/// | atime on read                                   | not updated          | updated, dirtying the INode                    |
+/// | mtime and ctime on write and directory changes  | not updated          | updated, dirtying the INode                    |
+/// | unlinked INodes still in use                    | kept until dropped   | kept until dropped                             |
+/// | atime on read-only storage like `StaticStorage` | not updated          | not updated                                    |
+///
+/// Keeping unlinked INodes in use and the errors returned do not depend on the strictness.
+///
+/// The default is `Relaxed`, or `Posix` with feature `posix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strictness {
+    Relaxed,
+    Posix,
+}
+
+impl Default for Strictness {
+    fn default() -> Self {
+        if cfg!(feature = "posix") {
+            Strictness::Posix
+        } else {
+            Strictness::Relaxed
+        }
+    }
+}
+
+/// The entry `move_` is moving onto
+#[derive(Debug, PartialEq, Eq)]
+enum MoveTarget {
+    /// There is no entry with the new name
+    Free,
+    /// The entry is the moved file itself, there is nothing to do
+    Same,
+    /// The entry is replaced, in POSIX mode
+    Replace,
+}
+
 /// inode for SEFS
 pub struct INodeImpl {
     /// inode number
     id: INodeId,
     /// on-disk inode
     disk_inode: RwLock<Dirty<DiskINode>>,
+    /// file type, never changes after creation
+    type_: FileType,
+    /// copy of `disk_inode.size`, readable without taking the lock
+    size: AtomicUsize,
+    /// copy of `disk_inode.nlinks`, readable without taking the lock
+    nlinks: AtomicUsize,
     /// back file
-    file: Box<dyn File>,
+    file: FileRef,
+    /// Held to read and held exclusively to write sealed blocks
+    seal_lock: RwLock<()>,
+    /// Held by `append`, so appends do not overwrite each other
+    append_lock: Mutex<()>,
     /// Reference to FS
     fs: Arc<SEFS>,
 }
@@ -74,17 +148,51 @@ impl Debug for INodeImpl {
 }
 
 impl INodeImpl {
+    /// Get the chattr-style flags, see `INODE_FLAG_*`
+    pub fn flags(&self) -> u32 {
+        self.disk_inode.read().flags
+    }
+    /// Set the chattr-style flags, see `INODE_FLAG_*`.
+    ///
+    /// `INODE_FLAG_SEALED` can only be changed on an empty file.
+    pub fn set_flags(&self, flags: u32) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        if flags & !(INODE_FLAG_IMMUTABLE | INODE_FLAG_APPEND | INODE_FLAG_SEALED) != 0 {
+            return Err(FsError::InvalidParam);
+        }
+        let mut disk_inode = self.disk_inode.write();
+        if (flags ^ disk_inode.flags) & INODE_FLAG_SEALED != 0
+            && (self.type_ != FileType::File || disk_inode.size != 0)
+        {
+            return Err(FsError::InvalidParam);
+        }
+        disk_inode.flags = flags;
+        Ok(())
+    }
+    /// Resize a File or SymLink, ignoring the flags
+    fn resize_data(&self, len: usize) -> vfs::Result<()> {
+        let sealer = self.sealer()?;
+        let _lock = sealer.map(|_| self.seal_lock.write());
+        let size = self.size.load(Ordering::Acquire);
+        match sealer {
+            Some(sealer) if size < len => self.write_sealed_at(sealer, size, len, &[], None)?,
+            Some(sealer) => self.truncate_sealed(sealer, len)?,
+            None => self.set_size(len)?,
+        }
+        self.touch_mtime();
+        Ok(())
+    }
     /// Only for Dir
-    fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
+    fn get_file_inode_and_entry_id(&self, name: &[u8]) -> Option<(INodeId, usize)> {
         (0..self.disk_inode.read().blocks as usize)
             .map(|i| {
                 let entry = self.file.read_direntry(i).unwrap();
                 (entry, i)
             })
-            .find(|(entry, _)| entry.name.as_ref() == name)
+            .find(|(entry, _)| entry.name.as_bytes() == name)
             .map(|(entry, id)| (entry.id as INodeId, id))
     }
-    fn get_file_inode_id(&self, name: &str) -> Option<INodeId> {
+    fn get_file_inode_id(&self, name: &[u8]) -> Option<INodeId> {
         self.get_file_inode_and_entry_id(name)
             .map(|(inode_id, _)| inode_id)
     }
@@ -116,6 +224,15 @@ impl INodeImpl {
         *total += 1;
         Ok(())
     }
+    /// Point '..' of a directory to `parent`
+    fn dirent_set_parent(&self, parent: INodeId) -> vfs::Result<()> {
+        let entry = DiskEntry {
+            id: parent as u32,
+            name: Str256::from(".."),
+        };
+        self.file.write_direntry(1, &entry)?;
+        Ok(())
+    }
     /// remove a page in middle of file and insert the last page here, useful for dirent remove
     /// should be only used in unlink
     fn dirent_remove(&self, id: usize) -> vfs::Result<()> {
@@ -130,36 +247,343 @@ impl INodeImpl {
         Ok(())
     }
     fn nlinks_inc(&self) {
-        self.disk_inode.write().nlinks += 1;
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.nlinks += 1;
+        self.nlinks
+            .store(disk_inode.nlinks as usize, Ordering::Release);
     }
     fn nlinks_dec(&self) {
         let mut disk_inode = self.disk_inode.write();
         assert!(disk_inode.nlinks > 0);
         disk_inode.nlinks -= 1;
+        self.nlinks
+            .store(disk_inode.nlinks as usize, Ordering::Release);
+    }
+    /// Lock-free read of `disk_inode.nlinks`
+    fn nlinks(&self) -> usize {
+        self.nlinks.load(Ordering::Acquire)
+    }
+    /// Fail if the INode is immutable, or append-only unless `append` is set
+    fn check_flags(&self, append: bool) -> vfs::Result<()> {
+        let flags = self.flags();
+        if flags & INODE_FLAG_IMMUTABLE != 0 || (flags & INODE_FLAG_APPEND != 0 && !append) {
+            return Err(FsError::OperationNotPermitted);
+        }
+        Ok(())
+    }
+    /// The sealer if the file is sealed, fail if the file system has none
+    fn sealer(&self) -> vfs::Result<Option<&'static dyn Sealer>> {
+        if self.flags() & INODE_FLAG_SEALED == 0 {
+            return Ok(None);
+        }
+        match *self.fs.sealer.read() {
+            Some((sealer, _)) => Ok(Some(sealer)),
+            None => Err(FsError::NotSupported),
+        }
+    }
+    /// Update atime in POSIX mode, unless the file system is read-only
+    fn touch_atime(&self) {
+        if self.fs.posix() && !self.fs.read_only {
+            self.disk_inode.write().atime = self.fs.now();
+        }
+    }
+    /// Update mtime and ctime in POSIX mode
+    fn touch_mtime(&self) {
+        if self.fs.posix() {
+            let now = self.fs.now();
+            let mut disk_inode = self.disk_inode.write();
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+        }
+    }
+    /// Update ctime in POSIX mode
+    fn touch_ctime(&self) {
+        if self.fs.posix() {
+            self.disk_inode.write().ctime = self.fs.now();
+        }
+    }
+    /// POSIX checks of `move_`: a directory can not be moved into itself,
+    /// and the existing `new_name` in `dest` can be replaced.
+    ///
+    /// Nothing is changed, the caller replaces the entry.
+    fn posix_move_check(
+        &self,
+        old_name: &[u8],
+        dest: &INodeImpl,
+        new_name: &[u8],
+    ) -> vfs::Result<MoveTarget> {
+        let inode_id = self
+            .get_file_inode_id(old_name)
+            .ok_or(FsError::EntryNotFound)?;
+        let inode = self.fs.get_inode(inode_id);
+        if inode.type_ == FileType::Dir && self.id != dest.id {
+            let mut dir = dest.id;
+            loop {
+                if dir == inode_id {
+                    return Err(FsError::InvalidParam);
+                }
+                let parent = self
+                    .fs
+                    .get_inode(dir)
+                    .get_file_inode_id(b"..")
+                    .ok_or(FsError::Corrupted)?;
+                if parent == dir {
+                    break;
+                }
+                if !self.fs.is_allocated(parent) {
+                    return Err(FsError::Corrupted);
+                }
+                dir = parent;
+            }
+        }
+        let old_id = match dest.get_file_inode_id(new_name) {
+            Some(id) => id,
+            None => return Ok(MoveTarget::Free),
+        };
+        if old_id == inode_id {
+            return Ok(MoveTarget::Same);
+        }
+        if new_name == b"." || new_name == b".." {
+            return Err(FsError::IsDir);
+        }
+        let old = self.fs.get_inode(old_id);
+        match (inode.type_ == FileType::Dir, old.type_ == FileType::Dir) {
+            (true, false) => return Err(FsError::NotDir),
+            (false, true) => return Err(FsError::IsDir),
+            (true, true) if old.disk_inode.read().blocks > 2 => return Err(FsError::DirNotEmpty),
+            _ => {}
+        }
+        dest.check_flags(false)?;
+        old.check_flags(false)?;
+        Ok(MoveTarget::Replace)
+    }
+    /// Read and unseal block `block_id` of a sealed file
+    fn read_sealed_block(
+        &self,
+        sealer: &dyn Sealer,
+        block_id: usize,
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<[u8; SEAL_BLOCK_SIZE]> {
+        let mut buf = [0u8; SEAL_TAG_SIZE + SEAL_BLOCK_SIZE];
+        let offset = block_id * buf.len();
+        if self.file.read_at_cancellable(&mut buf, offset, token)? != buf.len() {
+            return Err(FsError::Corrupted);
+        }
+        let mut tag = [0u8; SEAL_TAG_SIZE];
+        let mut block = [0u8; SEAL_BLOCK_SIZE];
+        tag.copy_from_slice(&buf[..SEAL_TAG_SIZE]);
+        block.copy_from_slice(&buf[SEAL_TAG_SIZE..]);
+        let generation = self.disk_inode.read().generation;
+        if !sealer.unseal(self.id, generation, block_id, &mut block, &tag) {
+            return Err(FsError::Corrupted);
+        }
+        Ok(block)
+    }
+    /// Seal and write `block` as block `block_id` of a sealed file
+    fn write_sealed_block(
+        &self,
+        sealer: &dyn Sealer,
+        block_id: usize,
+        mut block: [u8; SEAL_BLOCK_SIZE],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<()> {
+        let generation = self.disk_inode.read().generation;
+        let (_, rng) = self.fs.sealer.read().ok_or(FsError::NotSupported)?;
+        let tag = sealer.seal(rng, self.id, generation, block_id, &mut block)?;
+        let mut buf = [0u8; SEAL_TAG_SIZE + SEAL_BLOCK_SIZE];
+        buf[..SEAL_TAG_SIZE].copy_from_slice(&tag);
+        buf[SEAL_TAG_SIZE..].copy_from_slice(&block);
+        let offset = block_id * buf.len();
+        if self.file.write_at_cancellable(&buf, offset, token)? != buf.len() {
+            return Err(FsError::DeviceError);
+        }
+        Ok(())
+    }
+    /// Read `buf` at `offset` of a sealed file of `size` bytes
+    fn read_sealed(
+        &self,
+        sealer: &dyn Sealer,
+        size: usize,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<usize> {
+        let end = size.min(offset + buf.len());
+        let mut pos = offset;
+        while pos < end {
+            let begin = pos % SEAL_BLOCK_SIZE;
+            let len = (end - pos).min(SEAL_BLOCK_SIZE - begin);
+            let block = self.read_sealed_block(sealer, pos / SEAL_BLOCK_SIZE, token)?;
+            buf[pos - offset..pos - offset + len].copy_from_slice(&block[begin..begin + len]);
+            pos += len;
+        }
+        Ok(end.max(offset) - offset)
+    }
+    /// Write `buf` at `offset` of a sealed file of `old_size` bytes, which
+    /// has been resized to cover it. The gap after `old_size` is filled with zeros.
+    ///
+    /// Bytes after the end in the last block are kept zero.
+    fn write_sealed(
+        &self,
+        sealer: &dyn Sealer,
+        old_size: usize,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<()> {
+        let end = offset + buf.len();
+        let old_blocks = (old_size + SEAL_BLOCK_SIZE - 1) / SEAL_BLOCK_SIZE;
+        let blocks = (end + SEAL_BLOCK_SIZE - 1) / SEAL_BLOCK_SIZE;
+        for block_id in (offset / SEAL_BLOCK_SIZE).min(old_blocks)..blocks {
+            // the `len` bytes of `buf` at `begin` of the file fall into this block
+            let block_offset = block_id * SEAL_BLOCK_SIZE;
+            let begin = offset.max(block_offset).min(block_offset + SEAL_BLOCK_SIZE);
+            let len = end.min(block_offset + SEAL_BLOCK_SIZE).max(begin) - begin;
+            let mut block = match block_id < old_blocks && len < SEAL_BLOCK_SIZE {
+                true => self.read_sealed_block(sealer, block_id, token)?,
+                false => [0u8; SEAL_BLOCK_SIZE],
+            };
+            if len > 0 {
+                let block_begin = begin - block_offset;
+                block[block_begin..block_begin + len]
+                    .copy_from_slice(&buf[begin - offset..begin - offset + len]);
+            }
+            self.write_sealed_block(sealer, block_id, block, token)?;
+        }
+        Ok(())
+    }
+    /// Write `buf` at `offset` of a sealed file of `size` bytes, growing it if needed.
+    ///
+    /// If it fails, the file is truncated back to `size`, so no block after the
+    /// end is left unsealed.
+    fn write_sealed_at(
+        &self,
+        sealer: &dyn Sealer,
+        size: usize,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<()> {
+        let end = offset + buf.len();
+        if size < end {
+            self.set_size(end)?;
+        }
+        let result = self.write_sealed(sealer, size, offset, buf, token);
+        if result.is_err() && size < end {
+            // the error of the write is more useful than that of the truncation
+            self.truncate_sealed(sealer, size).ok();
+        }
+        result
+    }
+    /// Truncate a sealed file to `len` bytes, keeping the bytes after the end zero
+    fn truncate_sealed(&self, sealer: &dyn Sealer, len: usize) -> vfs::Result<()> {
+        self.set_size(len)?;
+        if len % SEAL_BLOCK_SIZE != 0 {
+            let block_id = len / SEAL_BLOCK_SIZE;
+            let mut block = self.read_sealed_block(sealer, block_id, None)?;
+            block[len % SEAL_BLOCK_SIZE..]
+                .iter_mut()
+                .for_each(|b| *b = 0);
+            self.write_sealed_block(sealer, block_id, block, None)?;
+        }
+        Ok(())
+    }
+    /// Set the size of file without checking the flags
+    fn set_size(&self, len: usize) -> vfs::Result<()> {
+        let len_on_storage = match self.flags() & INODE_FLAG_SEALED {
+            0 => len,
+            _ => (len + SEAL_BLOCK_SIZE - 1) / SEAL_BLOCK_SIZE * (SEAL_TAG_SIZE + SEAL_BLOCK_SIZE),
+        };
+        self.file.set_len(len_on_storage)?;
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.size = len as u32;
+        self.size.store(len, Ordering::Release);
+        Ok(())
+    }
+    /// Check the type and liveness of a directory without locking `disk_inode`
+    fn check_dir(&self) -> vfs::Result<()> {
+        if self.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if self.nlinks() == 0 {
+            return Err(FsError::DirRemoved);
+        }
+        Ok(())
+    }
+    /// Check a name to be created, any bytes except NUL and '/' are allowed
+    fn check_name(name: &[u8]) -> vfs::Result<()> {
+        if name.len() > MAX_FNAME_LEN {
+            return Err(FsError::InvalidParam);
+        }
+        vfs::check_name(name)
     }
 }
 
 impl vfs::INode for INodeImpl {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        let type_ = self.disk_inode.read().type_;
+        self.read_at_cancellable(offset, buf, None)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        self.write_at_cancellable(offset, buf, None)
+    }
+    fn read_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<usize> {
+        let type_ = self.type_;
         if type_ != FileType::File && type_ != FileType::SymLink {
             return Err(FsError::NotFile);
         }
-        let len = self.file.read_at(buf, offset)?;
+        let len = match self.sealer()? {
+            Some(sealer) => {
+                let _lock = self.seal_lock.read();
+                let size = self.size.load(Ordering::Acquire);
+                self.read_sealed(sealer, size, offset, buf, token)?
+            }
+            None => self.file.read_at_cancellable(buf, offset, token)?,
+        };
+        self.touch_atime();
         Ok(len)
     }
-    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        let DiskINode { type_, size, .. } = **self.disk_inode.read();
+    fn write_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<usize> {
+        self.fs.check_writable()?;
+        let type_ = self.type_;
         if type_ != FileType::File && type_ != FileType::SymLink {
             return Err(FsError::NotFile);
         }
+        let sealer = self.sealer()?;
+        let _lock = sealer.map(|_| self.seal_lock.write());
+        let size = self.size.load(Ordering::Acquire);
+        self.check_flags(offset >= size)?;
         let end_offset = offset + buf.len();
-        if (size as usize) < end_offset {
-            self.resize(end_offset)?;
-        }
-        let len = self.file.write_at(buf, offset)?;
+        let len = match sealer {
+            Some(sealer) => {
+                self.write_sealed_at(sealer, size, offset, buf, token)?;
+                buf.len()
+            }
+            None => {
+                if size < end_offset {
+                    self.set_size(end_offset)?;
+                }
+                self.file.write_at_cancellable(buf, offset, token)?
+            }
+        };
+        self.touch_mtime();
         Ok(len)
     }
+    fn append(&self, buf: &[u8]) -> vfs::Result<(usize, usize)> {
+        let _lock = self.append_lock.lock();
+        let offset = self.size.load(Ordering::Acquire);
+        let len = self.write_at(offset, buf)?;
+        Ok((offset, len))
+    }
     fn poll(&self) -> vfs::Result<vfs::PollStatus> {
         Ok(vfs::PollStatus {
             read: true,
@@ -201,6 +625,8 @@ impl vfs::INode for INodeImpl {
         })
     }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        self.check_flags(false)?;
         let mut disk_inode = self.disk_inode.write();
         disk_inode.mode = metadata.mode;
         disk_inode.uid = metadata.uid as u16;
@@ -226,13 +652,13 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn resize(&self, len: usize) -> vfs::Result<()> {
-        let type_ = self.disk_inode.read().type_;
+        self.fs.check_writable()?;
+        let type_ = self.type_;
         if type_ != FileType::File && type_ != FileType::SymLink {
             return Err(FsError::NotFile);
         }
-        self.file.set_len(len)?;
-        self.disk_inode.write().size = len as u32;
-        Ok(())
+        self.check_flags(false)?;
+        self.resize_data(len)
     }
     fn create(
         &self,
@@ -240,19 +666,24 @@ impl vfs::INode for INodeImpl {
         type_: vfs::FileType,
         mode: u32,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        self.create_bytes(name.as_bytes(), type_, mode)
+    }
+    fn create_bytes(
+        &self,
+        name: &[u8],
+        type_: vfs::FileType,
+        mode: u32,
+    ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        self.fs.check_writable()?;
+        Self::check_name(name)?;
         let type_ = match type_ {
             vfs::FileType::File => FileType::File,
             vfs::FileType::Dir => FileType::Dir,
             vfs::FileType::SymLink => FileType::SymLink,
             _ => return Err(vfs::FsError::InvalidParam),
         };
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks <= 0 {
-            return Err(FsError::DirRemoved);
-        }
+        self.check_dir()?;
+        self.check_flags(true)?;
 
         // Ensure the name is not exist
         if !self.get_file_inode_id(name).is_none() {
@@ -260,7 +691,10 @@ impl vfs::INode for INodeImpl {
         }
 
         // Create new INode
-        let inode = self.fs.new_inode(type_, mode as u16)?;
+        let umask = self.fs.umask.load(Ordering::Relaxed) as u16;
+        let inode = self
+            .fs
+            .new_inode_in(self, type_, mode as u16 & 0o7777 & !umask)?;
         if type_ == FileType::Dir {
             inode.dirent_init(self.id)?;
         }
@@ -280,17 +714,15 @@ impl vfs::INode for INodeImpl {
         Ok(inode)
     }
     fn unlink(&self, name: &str) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks <= 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if name == "." {
+        self.unlink_bytes(name.as_bytes())
+    }
+    fn unlink_bytes(&self, name: &[u8]) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        self.check_dir()?;
+        if name == b"." {
             return Err(FsError::IsDir);
         }
-        if name == ".." {
+        if name == b".." {
             return Err(FsError::IsDir);
         }
 
@@ -298,8 +730,10 @@ impl vfs::INode for INodeImpl {
             .get_file_inode_and_entry_id(name)
             .ok_or(FsError::EntryNotFound)?;
         let inode = self.fs.get_inode(inode_id);
+        self.check_flags(false)?;
+        inode.check_flags(false)?;
 
-        let type_ = inode.disk_inode.read().type_;
+        let type_ = inode.type_;
         if type_ == FileType::Dir {
             // only . and ..
             assert!(inode.disk_inode.read().blocks >= 2);
@@ -313,17 +747,18 @@ impl vfs::INode for INodeImpl {
             self.nlinks_dec(); //for ..
         }
         self.dirent_remove(entry_id)?;
+        self.touch_mtime();
+        inode.touch_ctime();
 
         Ok(())
     }
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks <= 0 {
-            return Err(FsError::DirRemoved);
-        }
+        self.link_bytes(name.as_bytes(), other)
+    }
+    fn link_bytes(&self, name: &[u8], other: &Arc<dyn INode>) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        Self::check_name(name)?;
+        self.check_dir()?;
         if !self.get_file_inode_id(name).is_none() {
             return Err(FsError::EntryExist);
         }
@@ -333,95 +768,125 @@ impl vfs::INode for INodeImpl {
         if !Arc::ptr_eq(&self.fs, &child.fs) {
             return Err(FsError::NotSameFs);
         }
-        if child.metadata()?.type_ == vfs::FileType::Dir {
+        if child.type_ == FileType::Dir {
             return Err(FsError::IsDir);
         }
+        self.check_flags(true)?;
+        child.check_flags(false)?;
         let entry = DiskEntry {
             id: child.id as u32,
             name: Str256::from(name),
         };
         self.dirent_append(&entry)?;
         child.nlinks_inc();
+        self.touch_mtime();
+        child.touch_ctime();
         Ok(())
     }
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks <= 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if old_name == "." {
+        self.move_bytes(old_name.as_bytes(), target, new_name.as_bytes())
+    }
+    fn move_bytes(
+        &self,
+        old_name: &[u8],
+        target: &Arc<dyn INode>,
+        new_name: &[u8],
+    ) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        Self::check_name(new_name)?;
+        self.check_dir()?;
+        if old_name == b"." {
             return Err(FsError::IsDir);
         }
-        if old_name == ".." {
+        if old_name == b".." {
             return Err(FsError::IsDir);
         }
 
         let dest = target
             .downcast_ref::<INodeImpl>()
             .ok_or(FsError::NotSameFs)?;
-        let dest_info = dest.metadata()?;
         if !Arc::ptr_eq(&self.fs, &dest.fs) {
             return Err(FsError::NotSameFs);
         }
-        if dest_info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if dest_info.nlinks <= 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if dest.get_file_inode_id(new_name).is_some() {
-            return Err(FsError::EntryExist);
+        dest.check_dir()?;
+        let target = match self.fs.posix() {
+            true => self.posix_move_check(old_name, dest, new_name)?,
+            false if dest.get_file_inode_id(new_name).is_some() => return Err(FsError::EntryExist),
+            false => MoveTarget::Free,
+        };
+        if target == MoveTarget::Same {
+            return Ok(());
         }
 
         let (inode_id, entry_id) = self
             .get_file_inode_and_entry_id(old_name)
             .ok_or(FsError::EntryNotFound)?;
-        if info.inode == dest_info.inode {
+        self.check_flags(false)?;
+        dest.check_flags(true)?;
+        let inode = self.fs.get_inode(inode_id);
+        inode.check_flags(false)?;
+        let entry = DiskEntry {
+            id: inode_id as u32,
+            name: Str256::from(new_name),
+        };
+        if target == MoveTarget::Replace {
+            let (old_id, old_entry_id) = dest
+                .get_file_inode_and_entry_id(new_name)
+                .ok_or(FsError::EntryNotFound)?;
+            let old = self.fs.get_inode(old_id);
+            // point the entry to the moved INode, so `new_name` is never missing,
+            // and nothing has changed if it fails
+            dest.file.write_direntry(old_entry_id, &entry)?;
+            self.dirent_remove(entry_id)?;
+            old.nlinks_dec();
+            if old.type_ == FileType::Dir {
+                old.nlinks_dec(); //for .
+                dest.nlinks_dec(); //for ..
+            }
+            old.touch_ctime();
+        } else if self.id == dest.id {
             // rename: in place modify name
-            let entry = DiskEntry {
-                id: inode_id as u32,
-                name: Str256::from(new_name),
-            };
             self.file.write_direntry(entry_id, &entry)?;
         } else {
-            // move
-            let inode = self.fs.get_inode(inode_id);
-
-            let entry = DiskEntry {
-                id: inode_id as u32,
-                name: Str256::from(new_name),
-            };
             dest.dirent_append(&entry)?;
             self.dirent_remove(entry_id)?;
-
-            if inode.metadata()?.type_ == vfs::FileType::Dir {
+        }
+        if self.id != dest.id {
+            if inode.type_ == FileType::Dir {
                 self.nlinks_dec();
                 dest.nlinks_inc();
+                inode.dirent_set_parent(dest.id)?;
             }
+            dest.touch_mtime();
         }
+        self.touch_mtime();
 
         Ok(())
     }
     fn find(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
+        self.find_bytes(name.as_bytes())
+    }
+    fn find_bytes(&self, name: &[u8]) -> vfs::Result<Arc<dyn vfs::INode>> {
+        if self.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
         let inode_id = self.get_file_inode_id(name).ok_or(FsError::EntryNotFound)?;
         Ok(self.fs.get_inode(inode_id))
     }
+    /// Non UTF-8 names are converted lossily, use `get_entry_bytes()` to get the exact name.
     fn get_entry(&self, id: usize) -> vfs::Result<String> {
-        if self.disk_inode.read().type_ != FileType::Dir {
+        let name = self.get_entry_bytes(id)?;
+        Ok(String::from_utf8_lossy(&name).into_owned())
+    }
+    fn get_entry_bytes(&self, id: usize) -> vfs::Result<Vec<u8>> {
+        if self.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
         if id >= self.disk_inode.read().blocks as usize {
             return Err(FsError::EntryNotFound);
         };
         let entry = self.file.read_direntry(id)?;
-        Ok(String::from(entry.name.as_ref()))
+        Ok(Vec::from(entry.name.as_bytes()))
     }
     fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
         Err(FsError::NotSupported)
@@ -432,6 +897,14 @@ impl vfs::INode for INodeImpl {
     fn fs(&self) -> Arc<dyn vfs::FileSystem> {
         self.fs.clone()
     }
+    fn query_extension(&self, id: ExtensionId) -> Option<&dyn Any> {
+        match id {
+            EXT_FILE_ID => Some(&self.id),
+            EXT_FS => Some(&self.fs),
+            EXT_INODE => Some(self),
+            _ => None,
+        }
+    }
     fn as_any_ref(&self) -> &dyn Any {
         self
     }
@@ -442,14 +915,31 @@ impl Drop for INodeImpl {
     fn drop(&mut self) {
         self.sync_all()
             .expect("Failed to sync when dropping the SEFS Inode");
-        if self.disk_inode.read().nlinks <= 0 {
+        if self.nlinks() == 0 {
             self.disk_inode.write().sync();
-            self.fs.free_block(self.id);
+            // remove the file before the id can be reused
             self.fs.device.remove(self.id).unwrap();
+            self.fs.free_block(self.id);
         }
     }
 }
 
+/// Generations given to new INodes, so a Sealer can tell a file from an
+/// earlier one with the same id.
+///
+/// `SuperBlock::next_generation` is set to `reserved` on disk before any
+/// generation up to it is handed out, so none is used twice, even if the file
+/// system was not synced before a crash.
+struct Generations {
+    /// The next generation to hand out
+    next: u32,
+    /// Generations below it may be in use
+    reserved: u32,
+}
+
+/// Number of generations reserved on disk at a time
+const GENERATIONS_RESERVED: u32 = 1024;
+
 /// Simple Encrypted File System
 pub struct SEFS {
     /// on-disk superblock
@@ -461,9 +951,22 @@ pub struct SEFS {
     /// device
     device: Box<dyn Storage>,
     /// metadata file
-    meta_file: Box<dyn File>,
+    meta_file: FileRef,
     /// Time provider
     time_provider: &'static dyn TimeProvider,
+    /// Permission bits cleared from new INodes
+    umask: AtomicUsize,
+    /// Sealer of files with `INODE_FLAG_SEALED`, and where it takes nonces from
+    sealer: RwLock<Option<(&'static dyn Sealer, &'static dyn RngProvider)>>,
+    /// Ids taken from the free map by directories for new INodes in them.
+    /// Kept until `sync()` gives them back, and counted as free in `info()`.
+    id_pools: Mutex<BTreeMap<INodeId, Vec<INodeId>>>,
+    /// Generations given to new INodes
+    generations: Mutex<Generations>,
+    /// Follow POSIX strictly, see `Strictness`
+    posix: AtomicBool,
+    /// Reject all changes, set when the storage is read-only
+    read_only: bool,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SEFS>,
 }
@@ -474,11 +977,13 @@ impl SEFS {
         device: Box<dyn Storage>,
         time_provider: &'static dyn TimeProvider,
     ) -> vfs::Result<Arc<Self>> {
-        let meta_file = device.open(0)?;
+        let meta_file = device.open_ref(0)?;
+        let read_only = device.is_read_only();
         let super_block = meta_file.load_struct::<SuperBlock>(BLKN_SUPER)?;
         if !super_block.check() {
             return Err(FsError::WrongFs);
         }
+        let next_generation = super_block.next_generation;
 
         // load free map
         let mut free_map = BitVec::with_capacity(BLKBITS * super_block.groups as usize);
@@ -500,22 +1005,40 @@ impl SEFS {
             device,
             meta_file,
             time_provider,
+            umask: AtomicUsize::new(0),
+            sealer: RwLock::new(None),
+            id_pools: Mutex::new(BTreeMap::new()),
+            generations: Mutex::new(Generations {
+                next: next_generation,
+                reserved: next_generation,
+            }),
+            posix: AtomicBool::new(Strictness::default() == Strictness::Posix),
+            read_only,
             self_ptr: Weak::default(),
         }
         .wrap())
     }
-    /// Create a new SEFS
+    /// Create a new SEFS, with a UUID from `rng_provider`
     pub fn create(
         device: Box<dyn Storage>,
         time_provider: &'static dyn TimeProvider,
+        rng_provider: &'static dyn RngProvider,
     ) -> vfs::Result<Arc<Self>> {
         let blocks = BLKBITS;
+        let mut uuid = [0u8; 16];
+        rng_provider.fill_bytes(&mut uuid)?;
+        // version 4, variant 1
+        uuid[6] = (uuid[6] & 0x0f) | 0x40;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
 
         let super_block = SuperBlock {
             magic: MAGIC,
             blocks: blocks as u32,
             unused_blocks: blocks as u32 - 2,
             groups: 1,
+            root_table: 0,
+            uuid,
+            next_generation: 0,
         };
         let free_map = {
             let mut bitset = BitVec::with_capacity(BLKBITS);
@@ -525,7 +1048,10 @@ impl SEFS {
             }
             bitset
         };
-        let meta_file = device.create(0)?;
+        if device.is_read_only() {
+            return Err(FsError::ReadOnlyFs);
+        }
+        let meta_file = FileRef::Boxed(device.create(0)?);
         meta_file.set_len(blocks * BLKSIZE)?;
 
         let sefs = SEFS {
@@ -535,6 +1061,15 @@ impl SEFS {
             device,
             meta_file,
             time_provider,
+            umask: AtomicUsize::new(0),
+            sealer: RwLock::new(None),
+            id_pools: Mutex::new(BTreeMap::new()),
+            generations: Mutex::new(Generations {
+                next: 0,
+                reserved: 0,
+            }),
+            posix: AtomicBool::new(Strictness::default() == Strictness::Posix),
+            read_only: false,
             self_ptr: Weak::default(),
         }
         .wrap();
@@ -567,6 +1102,13 @@ impl SEFS {
     fn alloc_block(&self) -> Option<usize> {
         let mut free_map = self.free_map.write();
         let mut super_block = self.super_block.write();
+        Some(self.alloc_block_locked(&mut free_map, &mut super_block))
+    }
+    fn alloc_block_locked(
+        &self,
+        free_map: &mut Dirty<BitVec<Lsb0, u8>>,
+        super_block: &mut Dirty<SuperBlock>,
+    ) -> usize {
         let id = free_map.alloc().or_else(|| {
             // allocate a new group
             let new_group_id = super_block.groups as usize;
@@ -583,7 +1125,29 @@ impl SEFS {
         });
         assert!(id.is_some(), "allocate block should always success");
         super_block.unused_blocks -= 1;
-        id
+        id.unwrap()
+    }
+    /// Return the ids in all pools to the free map
+    fn release_id_pools(
+        &self,
+        free_map: &mut Dirty<BitVec<Lsb0, u8>>,
+        super_block: &mut Dirty<SuperBlock>,
+    ) {
+        let mut id_pools = self.id_pools.lock();
+        for id in id_pools.values().flatten() {
+            free_map.set(*id, true);
+            super_block.unused_blocks += 1;
+        }
+        id_pools.clear();
+    }
+    /// Number of ids in the pools of directories
+    fn pooled_ids(&self) -> usize {
+        self.id_pools.lock().values().map(|pool| pool.len()).sum()
+    }
+    /// Whether `id` is in use, false if it is out of range
+    fn is_allocated(&self, id: INodeId) -> bool {
+        let free_map = self.free_map.read();
+        id < free_map.len() && !free_map[id]
     }
     /// Free a block
     fn free_block(&self, block_id: usize) {
@@ -600,18 +1164,24 @@ impl SEFS {
         id: INodeId,
         disk_inode: Dirty<DiskINode>,
         create: bool,
-    ) -> Arc<INodeImpl> {
+    ) -> vfs::Result<Arc<INodeImpl>> {
+        let file = match create {
+            true => FileRef::Boxed(self.device.create(id)?),
+            false => self.device.open_ref(id)?,
+        };
         let inode = Arc::new(INodeImpl {
             id,
+            type_: disk_inode.type_,
+            size: AtomicUsize::new(disk_inode.size as usize),
+            nlinks: AtomicUsize::new(disk_inode.nlinks as usize),
             disk_inode: RwLock::new(disk_inode),
-            file: match create {
-                true => self.device.create(id).unwrap(),
-                false => self.device.open(id).unwrap(),
-            },
+            file,
+            seal_lock: RwLock::new(()),
+            append_lock: Mutex::new(()),
             fs: self.self_ptr.upgrade().unwrap(),
         });
         self.inodes.write().insert(id, Arc::downgrade(&inode));
-        inode
+        Ok(inode)
     }
     /// Get inode by id. Load if not in memory.
     /// ** Must ensure it's a valid INode **
@@ -626,12 +1196,62 @@ impl SEFS {
         }
         // Load if not in set, or is weak ref.
         let disk_inode = Dirty::new(self.meta_file.load_struct::<DiskINode>(id).unwrap());
-        self._new_inode(id, disk_inode, false)
+        self._new_inode(id, disk_inode, false).unwrap()
     }
     /// Create a new INode file
     fn new_inode(&self, type_: FileType, mode: u16) -> vfs::Result<Arc<INodeImpl>> {
         let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let time = self.time_provider.current_time().sec as u32;
+        self.init_inode(id, type_, mode).map_err(|e| {
+            self.free_block(id);
+            e
+        })
+    }
+    /// Create a new INode file in `dir`, taking the id from the pool of `dir`,
+    /// or of another directory if it is empty
+    fn new_inode_in(
+        &self,
+        dir: &INodeImpl,
+        type_: FileType,
+        mode: u16,
+    ) -> vfs::Result<Arc<INodeImpl>> {
+        let pooled = {
+            let mut id_pools = self.id_pools.lock();
+            match id_pools.get_mut(&dir.id).and_then(|pool| pool.pop()) {
+                Some(id) => Some(id),
+                None => id_pools.values_mut().find_map(|pool| pool.pop()),
+            }
+        };
+        let id = match pooled {
+            Some(id) => id,
+            None => {
+                // fill the pool with the allocator locked,
+                // so `sync()` sees every id either allocated or pooled
+                let mut free_map = self.free_map.write();
+                let mut super_block = self.super_block.write();
+                let mut ids: Vec<_> = (0..INODE_POOL_BATCH)
+                    .map(|_| self.alloc_block_locked(&mut free_map, &mut super_block))
+                    .collect();
+                // hand out in ascending order
+                ids.reverse();
+                let id = ids.pop().unwrap();
+                let mut id_pools = self.id_pools.lock();
+                id_pools.entry(dir.id).or_insert_with(Vec::new).extend(ids);
+                id
+            }
+        };
+        self.init_inode(id, type_, mode).map_err(|e| {
+            let mut id_pools = self.id_pools.lock();
+            id_pools.entry(dir.id).or_insert_with(Vec::new).push(id);
+            e
+        })
+    }
+    /// Create the INode `id` just allocated.
+    ///
+    /// The id is not freed if it fails.
+    fn init_inode(&self, id: INodeId, type_: FileType, mode: u16) -> vfs::Result<Arc<INodeImpl>> {
+        let time = self.now();
+        // the last INode with this id, or zeros if there was none
+        let last = self.meta_file.load_struct::<DiskINode>(id)?;
         let disk_inode = Dirty::new_dirty(DiskINode {
             size: 0,
             type_,
@@ -643,8 +1263,171 @@ impl SEFS {
             atime: time,
             mtime: time,
             ctime: time,
+            flags: 0,
+            generation: self.next_generation(last.generation)?,
+            audit_head: [0; 32],
         });
-        Ok(self._new_inode(id, disk_inode, true))
+        self._new_inode(id, disk_inode, true)
+    }
+    /// Take a generation for a new INode whose id was last used at `last`
+    fn next_generation(&self, last: u32) -> vfs::Result<u32> {
+        let mut generations = self.generations.lock();
+        let generation = generations.next.max(last.wrapping_add(1));
+        if generation >= generations.reserved {
+            let reserved = generation.wrapping_add(GENERATIONS_RESERVED);
+            self.meta_file.write_all_at(
+                &reserved.to_ne_bytes(),
+                BLKSIZE * BLKN_SUPER + SuperBlock::NEXT_GENERATION_OFFSET,
+            )?;
+            self.meta_file.flush()?;
+            generations.reserved = reserved;
+        }
+        generations.next = generation.wrapping_add(1);
+        Ok(generation)
+    }
+    /// Create a new root directory `name`, which is independent of the default root.
+    ///
+    /// Named roots are recorded in the root table, a directory which is not
+    /// linked from any tree and whose inode id is kept in the superblock.
+    /// The parent of a named root is itself.
+    pub fn create_root(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
+        self.create_root_bytes(name.as_bytes())
+    }
+    /// Byte version of `create_root()`, see `INode::find_bytes()`
+    pub fn create_root_bytes(&self, name: &[u8]) -> vfs::Result<Arc<dyn vfs::INode>> {
+        self.check_writable()?;
+        INodeImpl::check_name(name)?;
+        if name == b"." || name == b".." {
+            return Err(FsError::InvalidParam);
+        }
+        let table = self.root_table_or_create()?;
+        if table.get_file_inode_id(name).is_some() {
+            return Err(FsError::EntryExist);
+        }
+        let root = self.new_inode(FileType::Dir, 0o777)?;
+        root.dirent_init(root.id)?;
+        root.nlinks_inc(); //for .
+        root.nlinks_inc(); //for ..
+        table.dirent_append(&DiskEntry {
+            id: root.id as u32,
+            name: Str256::from(name),
+        })?;
+        Ok(root)
+    }
+    /// Byte version of `root_named()`, see `INode::find_bytes()`
+    pub fn root_named_bytes(&self, name: &[u8]) -> vfs::Result<Arc<dyn vfs::INode>> {
+        if name == b"." || name == b".." {
+            return Err(FsError::InvalidParam);
+        }
+        let table = self.root_table().ok_or(FsError::EntryNotFound)?;
+        let id = table
+            .get_file_inode_id(name)
+            .ok_or(FsError::EntryNotFound)?;
+        Ok(self.get_inode(id))
+    }
+    /// Find files in the storage which do not belong to any allocated inode,
+    /// e.g. left by a crash or put there by the host. Return their ids.
+    ///
+    /// Remove them unless `dry_run` is set.
+    pub fn sweep_orphans(&self, dry_run: bool) -> vfs::Result<Vec<usize>> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+        // keep ids from being allocated until the orphans are removed
+        let free_map = self.free_map.write();
+        let mut orphans: Vec<usize> = self
+            .device
+            .list()?
+            .into_iter()
+            // file 0 is the meta file
+            .filter(|&id| id != 0 && (id >= free_map.len() || free_map[id]))
+            .collect();
+        orphans.sort();
+        if !dry_run {
+            for &id in orphans.iter() {
+                warn!("remove orphan file {}", id);
+                self.device.remove(id)?;
+            }
+        }
+        Ok(orphans)
+    }
+    /// Set the permission bits cleared from new INodes, return the old value
+    pub fn set_umask(&self, umask: u16) -> u16 {
+        self.umask.swap((umask & 0o777) as usize, Ordering::Relaxed) as u16
+    }
+    /// Set how strictly POSIX is followed, see `Strictness` for the differences
+    pub fn set_strictness(&self, strictness: Strictness) {
+        self.posix
+            .store(strictness == Strictness::Posix, Ordering::Relaxed);
+    }
+    pub fn strictness(&self) -> Strictness {
+        match self.posix() {
+            true => Strictness::Posix,
+            false => Strictness::Relaxed,
+        }
+    }
+    fn posix(&self) -> bool {
+        self.posix.load(Ordering::Relaxed)
+    }
+    /// Whether all changes are rejected, because the storage is read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    fn check_writable(&self) -> vfs::Result<()> {
+        match self.read_only {
+            true => Err(FsError::ReadOnlyFs),
+            false => Ok(()),
+        }
+    }
+    /// Current time for timestamps
+    fn now(&self) -> u32 {
+        self.time_provider.current_time().sec as u32
+    }
+    /// Set the sealer of files with `INODE_FLAG_SEALED`, see `seal`.
+    ///
+    /// `rng_provider` is passed to the sealer for its nonces.
+    pub fn set_sealer(&self, sealer: &'static dyn Sealer, rng_provider: &'static dyn RngProvider) {
+        *self.sealer.write() = Some((sealer, rng_provider));
+    }
+    /// UUID generated on creation, all zero for images created without one
+    pub fn uuid(&self) -> [u8; 16] {
+        self.super_block.read().uuid
+    }
+    /// Get the root table. Return `None` if no named root has been created.
+    fn root_table(&self) -> Option<Arc<INodeImpl>> {
+        let id = self.super_block.read().root_table as INodeId;
+        match id {
+            0 => None,
+            id => Some(self.get_inode(id)),
+        }
+    }
+    /// Get the root table, create it if there is none
+    fn root_table_or_create(&self) -> vfs::Result<Arc<INodeImpl>> {
+        // declared before the locks, so if it fails to init, it is dropped
+        // and its id is freed after the locks are released
+        let table;
+        let mut free_map = self.free_map.write();
+        let mut super_block = self.super_block.write();
+        if super_block.root_table != 0 {
+            drop(super_block);
+            drop(free_map);
+            return Ok(self.root_table().unwrap());
+        }
+        // create it with the allocator locked, so concurrent callers do not both create one
+        let id = self.alloc_block_locked(&mut free_map, &mut super_block);
+        table = match self.init_inode(id, FileType::Dir, 0o700) {
+            Ok(table) => table,
+            Err(e) => {
+                free_map.set(id, true);
+                super_block.unused_blocks += 1;
+                return Err(e);
+            }
+        };
+        table.dirent_init(id)?;
+        table.nlinks_inc(); //for .
+        table.nlinks_inc(); //for ..
+        super_block.root_table = id as u32;
+        Ok(table)
     }
     fn flush_weak_inodes(&self) {
         let mut inodes = self.inodes.write();
@@ -657,6 +1440,21 @@ impl SEFS {
             inodes.remove(&id);
         }
     }
+    /// Write the super block and the free map to the meta file
+    fn write_alloc_state(
+        &self,
+        free_map: &BitVec<Lsb0, u8>,
+        super_block: &SuperBlock,
+    ) -> vfs::Result<()> {
+        self.meta_file
+            .write_all_at(super_block.as_buf(), BLKSIZE * BLKN_SUPER)?;
+        for i in 0..super_block.groups as usize {
+            let slice = &free_map.as_slice()[BLKSIZE * i..BLKSIZE * (i + 1)];
+            self.meta_file
+                .write_all_at(slice, BLKSIZE * Self::get_freemap_block_id_of_group(i))?;
+        }
+        Ok(())
+    }
     fn get_freemap_block_id_of_group(group_id: usize) -> usize {
         BLKBITS * group_id + BLKN_FREEMAP
     }
@@ -665,23 +1463,20 @@ impl SEFS {
 impl vfs::FileSystem for SEFS {
     /// Write back super block if dirty
     fn sync(&self) -> vfs::Result<()> {
-        // sync super_block
-        let mut super_block = self.super_block.write();
-        if super_block.dirty() {
-            self.meta_file
-                .write_all_at(super_block.as_buf(), BLKSIZE * BLKN_SUPER)?;
-            super_block.sync();
-        }
-        // sync free_map
         let mut free_map = self.free_map.write();
-        if free_map.dirty() {
-            for i in 0..super_block.groups as usize {
-                let slice = &free_map.as_slice()[BLKSIZE * i..BLKSIZE * (i + 1)];
-                self.meta_file
-                    .write_all_at(slice, BLKSIZE * Self::get_freemap_block_id_of_group(i))?;
-            }
+        let mut super_block = self.super_block.write();
+        // the pooled ids are saved as free, so they are not lost
+        self.release_id_pools(&mut free_map, &mut super_block);
+        if free_map.dirty() || super_block.dirty() {
+            // keep a newer reservation from being saved before this one
+            let generations = self.generations.lock();
+            super_block.next_generation = generations.reserved;
+            self.write_alloc_state(&free_map, &super_block)?;
             free_map.sync();
+            super_block.sync();
         }
+        drop(super_block);
+        drop(free_map);
         // sync all INodes
         self.flush_weak_inodes();
         for inode in self.inodes.read().values() {
@@ -697,19 +1492,55 @@ impl vfs::FileSystem for SEFS {
         self.get_inode(BLKN_ROOT)
     }
 
+    fn root_named(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
+        self.root_named_bytes(name.as_bytes())
+    }
+
     fn info(&self) -> vfs::FsInfo {
         let sb = self.super_block.read();
+        let unused_blocks = sb.unused_blocks as usize + self.pooled_ids();
         vfs::FsInfo {
             bsize: BLKSIZE,
             frsize: BLKSIZE,
             blocks: sb.blocks as usize,
-            bfree: sb.unused_blocks as usize,
-            bavail: sb.unused_blocks as usize,
-            files: sb.blocks as usize,        // inaccurate
-            ffree: sb.unused_blocks as usize, // inaccurate
+            bfree: unused_blocks,
+            bavail: unused_blocks,
+            files: sb.blocks as usize, // inaccurate
+            ffree: unused_blocks,      // inaccurate
             namemax: MAX_FNAME_LEN,
         }
     }
+
+    fn run_maintenance(&self, budget: usize) -> vfs::Result<usize> {
+        maintenance::run_tasks(&[&Flusher(self)], budget)
+    }
+}
+
+/// Write back dirty INodes, one per unit of work
+struct Flusher<'a>(&'a SEFS);
+
+impl MaintenanceTask for Flusher<'_> {
+    fn run(&self, budget: usize) -> vfs::Result<usize> {
+        self.0.flush_weak_inodes();
+        let inodes: Vec<_> = self
+            .0
+            .inodes
+            .read()
+            .values()
+            .filter_map(|inode| inode.upgrade())
+            .collect();
+        let mut done = 0;
+        for inode in inodes {
+            if done >= budget {
+                break;
+            }
+            if inode.disk_inode.read().dirty() {
+                inode.sync_all()?;
+                done += 1;
+            }
+        }
+        Ok(done)
+    }
 }
 
 impl Drop for SEFS {
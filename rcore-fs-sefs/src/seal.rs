@@ -0,0 +1,49 @@
+//! Sealing of selected files
+//!
+//! Files with `INODE_FLAG_SEALED` go through the `Sealer` set by
+//! `SEFS::set_sealer()`: data is sealed before it reaches the storage and
+//! unsealed after it is read back. Other files stay in plaintext.
+//!
+//! Sealed files are stored in blocks of `SEAL_BLOCK_SIZE` bytes, each after
+//! its tag of `SEAL_TAG_SIZE` bytes. A write seals the blocks it touches again,
+//! so a block is sealed many times at the same place. The sealer must use an
+//! authenticated cipher with a fresh nonce for each call, e.g. AES-GCM with a
+//! nonce from the given `RngProvider` stored in the tag next to the MAC. The
+//! file id, the generation and the block id should be authenticated as well,
+//! so a block can not be moved to another place, or to a new file reusing the
+//! id of a removed one. The provider is the one passed to `set_sealer()`, so
+//! a seeded one makes sealed files reproducible.
+//!
+//! A block can still be replaced by an older version of itself. Files needing
+//! freshness should keep a hash of their content elsewhere, e.g. in an `audit` log.
+
+use rcore_fs::dev::{DevError, RngProvider};
+
+/// Size of the blocks sealed files are stored in
+pub const SEAL_BLOCK_SIZE: usize = 0x1000;
+/// Size of the tag stored before each sealed block, e.g. a nonce and a MAC
+pub const SEAL_TAG_SIZE: usize = 32;
+
+/// Seal and unseal file data in place, usually backed by an enclave key
+pub trait Sealer: Send + Sync {
+    /// Seal `block`, the plaintext of block `block_id` of file `file_id`
+    /// at `generation`, with a nonce from `rng`. Return the tag to store with it.
+    fn seal(
+        &self,
+        rng: &dyn RngProvider,
+        file_id: usize,
+        generation: u32,
+        block_id: usize,
+        block: &mut [u8; SEAL_BLOCK_SIZE],
+    ) -> Result<[u8; SEAL_TAG_SIZE], DevError>;
+    /// Unseal `block` stored with `tag` as block `block_id` of file `file_id`
+    /// at `generation`. Return false if it was sealed elsewhere or modified.
+    fn unseal(
+        &self,
+        file_id: usize,
+        generation: u32,
+        block_id: usize,
+        block: &mut [u8; SEAL_BLOCK_SIZE],
+        tag: &[u8; SEAL_TAG_SIZE],
+    ) -> bool;
+}
@@ -1,14 +1,17 @@
 //! On-disk structures in SEFS
 
-use alloc::str;
+use alloc::{str, string::String};
 use core::fmt::{Debug, Error, Formatter};
 use core::mem::{size_of, size_of_val};
 use core::slice;
+#[cfg(feature = "std")]
+use serde::{Deserialize, Serialize};
 use static_assertions::const_assert;
 
 /// On-disk superblock
 #[repr(C)]
 #[derive(Debug)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub struct SuperBlock {
     /// magic number, should be SFS_MAGIC
     pub magic: u32,
@@ -18,11 +21,18 @@ pub struct SuperBlock {
     pub unused_blocks: u32,
     /// number of block groups
     pub groups: u32,
+    /// inode id of the root table, 0 if there is no named root
+    pub root_table: u32,
+    /// random UUID generated on creation, all zero for old images
+    pub uuid: [u8; 16],
+    /// no INode has a generation from here on, it is saved before any is handed out
+    pub next_generation: u32,
 }
 
 /// On-disk inode
 #[repr(C)]
 #[derive(Debug)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub struct DiskINode {
     /// size of the file (in bytes)
     pub size: u32,
@@ -40,6 +50,12 @@ pub struct DiskINode {
     pub atime: u32,
     pub mtime: u32,
     pub ctime: u32,
+    /// chattr-style flags, see `INODE_FLAG_*`
+    pub flags: u32,
+    /// bumped each time the inode id is reused, see `seal`
+    pub generation: u32,
+    /// MAC of the last record if the file is an audit log, see `audit`
+    pub audit_head: [u8; 32],
 }
 
 /// On-disk file entry
@@ -55,28 +71,44 @@ pub struct DiskEntry {
 #[repr(C)]
 pub struct Str256(pub [u8; 256]);
 
+impl Str256 {
+    /// The raw name, not necessarily UTF-8
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(self.0.len());
+        &self.0[0..len]
+    }
+}
+
 impl AsRef<str> for Str256 {
     fn as_ref(&self) -> &str {
-        let len = self.0.iter().enumerate().find(|(_, &b)| b == 0).unwrap().0;
-        str::from_utf8(&self.0[0..len]).unwrap()
+        str::from_utf8(self.as_bytes()).unwrap()
     }
 }
 
 impl Debug for Str256 {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "{}", self.as_ref())
+        write!(f, "{}", String::from_utf8_lossy(self.as_bytes()))
     }
 }
 
 impl<'a> From<&'a str> for Str256 {
     fn from(s: &'a str) -> Self {
+        Str256::from(s.as_bytes())
+    }
+}
+
+impl<'a> From<&'a [u8]> for Str256 {
+    fn from(s: &'a [u8]) -> Self {
         let mut ret = [0u8; 256];
-        ret[0..s.len()].copy_from_slice(s.as_ref());
+        ret[0..s.len()].copy_from_slice(s);
         Str256(ret)
     }
 }
 
 impl SuperBlock {
+    /// Offset of `next_generation`, which is saved on its own
+    pub const NEXT_GENERATION_OFFSET: usize = 36;
+
     pub fn check(&self) -> bool {
         self.magic == MAGIC
     }
@@ -126,9 +158,17 @@ pub const BLKBITS: usize = BLKSIZE * 8;
 /// size of a dirent used in the size field
 pub const DIRENT_SIZE: usize = 260;
 
+/// inode flag: the file can not be modified, unlinked or renamed
+pub const INODE_FLAG_IMMUTABLE: u32 = 0x10;
+/// inode flag: the file can only be appended, and can not be unlinked or renamed
+pub const INODE_FLAG_APPEND: u32 = 0x20;
+/// inode flag: the data is sealed by the `Sealer` of the file system, see `seal`
+pub const INODE_FLAG_SEALED: u32 = 0x40;
+
 /// file types
 #[repr(u16)]
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub enum FileType {
     Invalid = 0,
     File = 1,
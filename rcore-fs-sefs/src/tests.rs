@@ -0,0 +1,1090 @@
+extern crate std;
+
+use crate::*;
+use rcore_fs::dev::std_impl::{StdRngProvider, StdTimeProvider};
+use rcore_fs::dev::{DevError, FixedTimeProvider, SeededRngProvider};
+use rcore_fs::vfs::{FileSystem, FileType, Result};
+use std::sync::atomic::AtomicBool;
+use tempfile::TempDir;
+
+fn _create_new_sefs() -> (Arc<SEFS>, TempDir) {
+    let dir = tempfile::tempdir().expect("failed to create dir");
+    let sefs = SEFS::create(
+        Box::new(StdStorage::new(dir.path())),
+        &StdTimeProvider,
+        &StdRngProvider,
+    )
+    .expect("failed to create SEFS");
+    (sefs, dir)
+}
+
+#[test]
+fn create_new_sefs() {
+    let (sefs, _dir) = _create_new_sefs();
+    let _root = sefs.root_inode();
+}
+
+#[test]
+fn create_file() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let info = file1.metadata()?;
+    assert_eq!(info.type_, FileType::File);
+    assert_eq!(info.size, 0);
+    assert_eq!(info.nlinks, 1);
+
+    assert_eq!(file1.write_at(0x10, b"hello")?, 5);
+    assert_eq!(file1.metadata()?.size, 0x15, "write_at should extend size");
+    file1.resize(3)?;
+    assert_eq!(file1.metadata()?.size, 3);
+
+    assert_eq!(
+        root.create("file1", FileType::File, 0o777).err(),
+        Some(FsError::EntryExist)
+    );
+    assert_eq!(file1.find("x").err(), Some(FsError::NotDir));
+
+    sefs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn link_unlink_move() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    assert_eq!(root.metadata()?.nlinks, 3);
+
+    dir1.link("file2", &file1)?;
+    assert_eq!(file1.metadata()?.nlinks, 2);
+    root.move_("file1", &dir1, "file3")?;
+    assert!(root.find("file1").is_err());
+    assert!(dir1.find("file3").is_ok());
+
+    assert_eq!(root.unlink("dir1").err(), Some(FsError::DirNotEmpty));
+    dir1.unlink("file2")?;
+    dir1.unlink("file3")?;
+    assert_eq!(file1.metadata()?.nlinks, 0);
+    root.unlink("dir1")?;
+    assert_eq!(root.metadata()?.nlinks, 2);
+    assert_eq!(
+        dir1.create("file4", FileType::File, 0o777).err(),
+        Some(FsError::DirRemoved)
+    );
+
+    sefs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn named_roots() -> Result<()> {
+    let (sefs, dir) = _create_new_sefs();
+    assert_eq!(sefs.root_named("a").err(), Some(FsError::EntryNotFound));
+    let root_a = sefs.create_root("a")?;
+    root_a.create("file_a", FileType::File, 0o777)?;
+    assert_eq!(sefs.create_root("a").err(), Some(FsError::EntryExist));
+    assert_eq!(sefs.create_root("..").err(), Some(FsError::InvalidParam));
+    for name in &["", "a/b", &"x".repeat(300)] {
+        assert_eq!(sefs.create_root(name).err(), Some(FsError::InvalidParam));
+    }
+    let root_b = sefs.create_root_bytes(b"\xffb")?;
+    assert!(Arc::ptr_eq(&sefs.root_named_bytes(b"\xffb")?, &root_b));
+    assert!(Arc::ptr_eq(&root_a.lookup("..")?, &root_a));
+    assert!(sefs.root_inode().find("file_a").is_err());
+    drop((root_a, root_b));
+    drop(sefs);
+
+    let sefs = SEFS::open(Box::new(StdStorage::new(dir.path())), &StdTimeProvider)?;
+    let root_a = sefs.root_named("a")?;
+    assert!(root_a.find("file_a").is_ok());
+    assert_eq!(sefs.root_named("b").err(), Some(FsError::EntryNotFound));
+    Ok(())
+}
+
+#[test]
+fn create_roots_concurrently() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let barrier = Arc::new(std::sync::Barrier::new(8));
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let (sefs, barrier) = (sefs.clone(), barrier.clone());
+            std::thread::spawn(move || {
+                barrier.wait();
+                sefs.create_root(&format!("root{}", i)).map(|_| ())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    // all roots are in the same table
+    for i in 0..8 {
+        sefs.root_named(&format!("root{}", i))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn sweep_orphans() -> Result<()> {
+    let (sefs, dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    root.create("file1", FileType::File, 0o777)?;
+    std::fs::write(dir.path().join("1000"), b"orphan").unwrap();
+    std::fs::write(dir.path().join("not_an_id"), b"").unwrap();
+    // not written for an id, so not taken for "7"
+    std::fs::write(dir.path().join("007"), b"").unwrap();
+    std::fs::write(dir.path().join("+7"), b"").unwrap();
+
+    assert_eq!(sefs.sweep_orphans(true)?, [1000]);
+    assert!(dir.path().join("1000").exists());
+    assert_eq!(sefs.sweep_orphans(false)?, [1000]);
+    assert!(!dir.path().join("1000").exists());
+    assert!(dir.path().join("not_an_id").exists());
+    assert!(dir.path().join("007").exists());
+    assert!(sefs.sweep_orphans(true)?.is_empty());
+    assert!(root.find("file1").is_ok());
+    Ok(())
+}
+
+#[test]
+fn sweep_orphans_concurrently() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let sweeper = {
+        let sefs = sefs.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for _ in 0..100 {
+                // files of live inodes are never orphans
+                assert!(sefs.sweep_orphans(false)?.is_empty());
+            }
+            Ok(())
+        })
+    };
+    for i in 0..100 {
+        let name = format!("file{}", i);
+        root.create(&name, FileType::File, 0o777)?
+            .write_at(0, b"data")?;
+        if i % 2 == 0 {
+            root.unlink(&name)?;
+        }
+    }
+    sweeper.join().unwrap()?;
+    for i in (1..100).step_by(2) {
+        let mut buf = [0u8; 4];
+        root.find(&format!("file{}", i))?.read_at(0, &mut buf)?;
+        assert_eq!(&buf, b"data");
+    }
+    Ok(())
+}
+
+#[test]
+fn storage_list_and_stat() {
+    let dir = tempfile::tempdir().expect("failed to create dir");
+    let storage = StdStorage::new(dir.path());
+    let file = storage.create(5).unwrap();
+    file.write_all_at(b"hello", 10).unwrap();
+    assert_eq!(storage.list().unwrap(), [5]);
+    assert_eq!(storage.stat(5).unwrap(), 15);
+    assert!(storage.stat(6).is_err());
+}
+
+#[test]
+fn run_maintenance() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    sefs.sync()?;
+    assert_eq!(sefs.run_maintenance(10)?, 0);
+
+    file1.resize(100)?;
+    file2.resize(100)?;
+    assert_eq!(sefs.run_maintenance(1)?, 1);
+    assert_eq!(sefs.run_maintenance(10)?, 1);
+    assert_eq!(sefs.run_maintenance(10)?, 0);
+    Ok(())
+}
+
+#[test]
+fn umask() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o100666)?;
+    assert_eq!(file1.metadata()?.mode, 0o666);
+
+    assert_eq!(sefs.set_umask(0o022), 0);
+    let file2 = root.create("file2", FileType::File, 0o666)?;
+    assert_eq!(file2.metadata()?.mode, 0o644);
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    assert_eq!(dir.metadata()?.mode, 0o755);
+    assert_eq!(sefs.set_umask(0), 0o022);
+    Ok(())
+}
+
+#[test]
+fn immutable_and_append_flags() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let file = root.create("file", FileType::File, 0o666)?;
+    file.write_at(0, b"hello")?;
+    let file_impl = file.downcast_ref::<INodeImpl>().unwrap();
+    assert_eq!(file_impl.set_flags(0x1), Err(FsError::InvalidParam));
+
+    file_impl.set_flags(INODE_FLAG_IMMUTABLE)?;
+    assert_eq!(file.write_at(5, b"!"), Err(FsError::OperationNotPermitted));
+    assert_eq!(file.resize(0), Err(FsError::OperationNotPermitted));
+    let metadata = file.metadata()?;
+    assert_eq!(
+        file.set_metadata(&metadata),
+        Err(FsError::OperationNotPermitted)
+    );
+    assert_eq!(root.unlink("file"), Err(FsError::OperationNotPermitted));
+    assert_eq!(
+        root.move_("file", &root, "file1"),
+        Err(FsError::OperationNotPermitted)
+    );
+    assert_eq!(
+        root.link("link", &file),
+        Err(FsError::OperationNotPermitted)
+    );
+
+    file_impl.set_flags(INODE_FLAG_APPEND)?;
+    assert_eq!(file.write_at(0, b"H"), Err(FsError::OperationNotPermitted));
+    assert_eq!(file.write_at(5, b" world")?, 6);
+    assert_eq!(file.resize(5), Err(FsError::OperationNotPermitted));
+    assert_eq!(root.unlink("file"), Err(FsError::OperationNotPermitted));
+
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    dir.create("file", FileType::File, 0o666)?;
+    let dir_impl = dir.downcast_ref::<INodeImpl>().unwrap();
+    dir_impl.set_flags(INODE_FLAG_APPEND)?;
+    assert!(dir.create("file1", FileType::File, 0o666).is_ok());
+    assert_eq!(dir.unlink("file"), Err(FsError::OperationNotPermitted));
+    dir_impl.set_flags(INODE_FLAG_IMMUTABLE)?;
+    assert_eq!(
+        dir.create("file2", FileType::File, 0o666).err(),
+        Some(FsError::OperationNotPermitted)
+    );
+
+    file_impl.set_flags(0)?;
+    root.unlink("file")?;
+    Ok(())
+}
+
+/// A toy MAC, not secure
+struct XorMac;
+
+impl audit::AuditMac for XorMac {
+    fn mac(&self, prev: &[u8; audit::MAC_SIZE], record: &[u8]) -> [u8; audit::MAC_SIZE] {
+        let mut mac = *prev;
+        for (i, &b) in record.iter().enumerate() {
+            let j = i % audit::MAC_SIZE;
+            mac[j] = mac[j].rotate_left(3) ^ b ^ (i as u8);
+        }
+        mac
+    }
+}
+
+#[test]
+fn audit_log() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let file = root.create("audit", FileType::File, 0o600)?;
+    let log = audit::AuditLog::open(file.clone(), &XorMac)?;
+    log.append(b"login alice")?;
+    log.append(b"logout alice")?;
+    let head = log.verify_chain()?;
+
+    // append-only
+    assert_eq!(
+        file.write_at(4, b"mallory"),
+        Err(FsError::OperationNotPermitted)
+    );
+    assert_eq!(root.unlink("audit"), Err(FsError::OperationNotPermitted));
+
+    // reopen
+    let log = audit::AuditLog::open(file.clone(), &XorMac)?;
+    assert_eq!(log.verify_chain()?, head);
+    log.append(b"login bob")?;
+    assert_ne!(log.verify_chain()?, head);
+
+    // cut off the last record
+    let file_impl = file.downcast_ref::<INodeImpl>().unwrap();
+    file_impl.set_flags(0)?;
+    file.resize(4 + 11 + audit::MAC_SIZE + 4 + 12 + audit::MAC_SIZE)?;
+    assert_eq!(log.verify_chain(), Err(FsError::Corrupted));
+
+    // tamper behind the FS
+    file.write_at(4, b"L")?;
+    assert_eq!(log.verify_chain(), Err(FsError::Corrupted));
+    assert!(audit::AuditLog::open(file.clone(), &XorMac).is_err());
+    Ok(())
+}
+
+/// Storage whose data files write only half of the buffer when `fail` is set
+struct ShortWriteStorage {
+    inner: StdStorage,
+    fail: Arc<AtomicBool>,
+}
+
+struct ShortWriteFile {
+    inner: Box<dyn dev::File>,
+    fail: Arc<AtomicBool>,
+}
+
+impl dev::File for ShortWriteFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> dev::DevResult<usize> {
+        self.inner.read_at(buf, offset)
+    }
+    fn write_at(&self, buf: &[u8], offset: usize) -> dev::DevResult<usize> {
+        match self.fail.load(Ordering::SeqCst) {
+            true => self.inner.write_at(&buf[..buf.len() / 2], offset),
+            false => self.inner.write_at(buf, offset),
+        }
+    }
+    fn set_len(&self, len: usize) -> dev::DevResult<()> {
+        self.inner.set_len(len)
+    }
+    fn flush(&self) -> dev::DevResult<()> {
+        self.inner.flush()
+    }
+}
+
+impl ShortWriteStorage {
+    fn wrap(&self, file_id: usize, file: Box<dyn dev::File>) -> Box<dyn dev::File> {
+        match file_id {
+            // keep the meta file intact
+            0 => file,
+            _ => Box::new(ShortWriteFile {
+                inner: file,
+                fail: self.fail.clone(),
+            }),
+        }
+    }
+}
+
+impl Storage for ShortWriteStorage {
+    fn open(&self, file_id: usize) -> dev::DevResult<Box<dyn dev::File>> {
+        Ok(self.wrap(file_id, self.inner.open(file_id)?))
+    }
+    fn create(&self, file_id: usize) -> dev::DevResult<Box<dyn dev::File>> {
+        Ok(self.wrap(file_id, self.inner.create(file_id)?))
+    }
+    fn remove(&self, file_id: usize) -> dev::DevResult<()> {
+        self.inner.remove(file_id)
+    }
+    fn list(&self) -> dev::DevResult<Vec<usize>> {
+        self.inner.list()
+    }
+    fn stat(&self, file_id: usize) -> dev::DevResult<usize> {
+        self.inner.stat(file_id)
+    }
+}
+
+#[test]
+fn audit_log_failed_append() -> Result<()> {
+    let dir = tempfile::tempdir().expect("failed to create dir");
+    let fail = Arc::new(AtomicBool::new(false));
+    let storage = ShortWriteStorage {
+        inner: StdStorage::new(dir.path()),
+        fail: fail.clone(),
+    };
+    let sefs = SEFS::create(Box::new(storage), &StdTimeProvider, &StdRngProvider)?;
+    let file = sefs.root_inode().create("audit", FileType::File, 0o600)?;
+    let log = audit::AuditLog::open(file.clone(), &XorMac)?;
+    log.append(b"login alice")?;
+    let size = file.metadata()?.size;
+
+    fail.store(true, Ordering::SeqCst);
+    assert!(log.append(b"logout alice").is_err());
+    fail.store(false, Ordering::SeqCst);
+    // the partial record is cut off and the log goes on
+    assert_eq!(file.metadata()?.size, size);
+    log.verify_chain()?;
+    log.append(b"logout alice")?;
+    let head = log.verify_chain()?;
+    assert_eq!(audit::AuditLog::open(file, &XorMac)?.verify_chain()?, head);
+    Ok(())
+}
+
+#[test]
+fn query_extension() -> Result<()> {
+    let (sefs, dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let file = root.create("file", FileType::File, 0o666)?;
+    let id = *file.extension::<usize>(EXT_FILE_ID).unwrap();
+    assert!(dir.path().join(format!("{}", id)).exists());
+    let fs = file.extension::<Arc<SEFS>>(EXT_FS).unwrap();
+    assert!(Arc::ptr_eq(fs, &sefs));
+    let inode = file.extension::<INodeImpl>(EXT_INODE).unwrap();
+    inode.set_flags(INODE_FLAG_APPEND)?;
+    assert!(file.extension::<usize>(EXT_FS).is_none());
+    assert!(file.query_extension(ExtensionId("unknown")).is_none());
+    Ok(())
+}
+
+#[test]
+fn non_utf8_name() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let name: &[u8] = b"\xff\xfe.txt";
+    let file = root.create_bytes(name, FileType::File, 0o666)?;
+    assert!(Arc::ptr_eq(&root.find_bytes(name)?, &file));
+    assert_eq!(root.list_bytes()?[2], name);
+    assert_eq!(root.get_entry(2)?, "\u{fffd}\u{fffd}.txt");
+    assert_eq!(
+        root.find("\u{fffd}\u{fffd}.txt").err(),
+        Some(FsError::EntryNotFound)
+    );
+
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    root.move_bytes(name, &dir, b"\x80")?;
+    dir.link_bytes(b"\x81", &file)?;
+    assert_eq!(dir.list_bytes()?[2..], [b"\x80".to_vec(), b"\x81".to_vec()]);
+    dir.unlink_bytes(b"\x80")?;
+    assert_eq!(file.metadata()?.nlinks, 1);
+
+    for name in [&b""[..], b"a/b", b"a\0b", &[b'a'; 256]].iter() {
+        assert_eq!(
+            root.create_bytes(name, FileType::File, 0o666).err(),
+            Some(FsError::InvalidParam)
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn sparse_storage() {
+    let dir = tempfile::tempdir().expect("failed to create dir");
+    let storage = StdStorage::new_sparse(dir.path());
+    let file = storage.create(1).unwrap();
+    let size = 0x100000;
+    file.write_all_at(&vec![0u8; size], 0).unwrap();
+    file.write_all_at(b"end", size).unwrap();
+    file.write_all_at(b"begin", 0).unwrap();
+    assert_eq!(storage.stat(1).unwrap(), size + 3);
+    assert!(storage.physical_size(1).unwrap() < size);
+
+    let mut buf = [1u8; 8];
+    file.read_exact_at(&mut buf, 0x1000).unwrap();
+    assert_eq!(buf, [0u8; 8]);
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(&buf, b"begin\0\0\0");
+    file.read_exact_at(&mut buf[..3], size).unwrap();
+    assert_eq!(&buf[..3], b"end");
+}
+
+#[test]
+fn sparse_sefs_file() -> Result<()> {
+    let dir = tempfile::tempdir().expect("failed to create dir");
+    let sefs = SEFS::create(
+        Box::new(StdStorage::new_sparse(dir.path())),
+        &StdTimeProvider,
+        &StdRngProvider,
+    )?;
+    let file = sefs.root_inode().create("file", FileType::File, 0o666)?;
+    let size = 0x100000;
+    file.write_at(0, &vec![0u8; size])?;
+    file.write_at(size, b"end")?;
+    file.write_at(0, b"begin")?;
+    sefs.sync()?;
+    let id = file.metadata()?.inode;
+    let storage = StdStorage::new_sparse(dir.path());
+    assert_eq!(storage.stat(id).unwrap(), size + 3);
+    assert!(storage.physical_size(id).unwrap() < size);
+
+    let mut buf = [1u8; 8];
+    file.read_at(0x1000, &mut buf)?;
+    assert_eq!(buf, [0u8; 8]);
+    file.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"begin\0\0\0");
+    Ok(())
+}
+
+/// Create a SEFS with "file" and "dir" in the root, then open it on `StaticStorage`
+fn _create_static_sefs() -> Result<Arc<SEFS>> {
+    let (sefs, dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    root.create("file", FileType::File, 0o666)?
+        .write_at(0, b"static")?;
+    root.create("dir", FileType::Dir, 0o777)?;
+    drop(root);
+    drop(sefs);
+
+    let mut files = Vec::new();
+    for id in StdStorage::new(dir.path()).list().unwrap() {
+        let data = std::fs::read(dir.path().join(format!("{}", id))).unwrap();
+        files.push((id, &*Box::leak(data.into_boxed_slice())));
+    }
+    let files = Box::leak(files.into_boxed_slice());
+    SEFS::open(Box::new(StaticStorage::new(files)), &StdTimeProvider)
+}
+
+#[test]
+fn static_storage() -> Result<()> {
+    let sefs = _create_static_sefs()?;
+    let file = sefs.root_inode().find("file")?;
+    let mut buf = [0u8; 6];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"static");
+    assert!(file.write_at(0, b"x").is_err());
+    // files are borrowed, not boxed
+    let file = file.downcast_ref::<INodeImpl>().unwrap();
+    assert!(matches!(file.file, FileRef::Static(_)));
+    Ok(())
+}
+
+#[test]
+fn static_storage_is_read_only() -> Result<()> {
+    let sefs = _create_static_sefs()?;
+    assert!(sefs.is_read_only());
+    let root = sefs.root_inode();
+    let file = root.find("file")?;
+    let dir = root.find("dir")?;
+    let err = Some(FsError::ReadOnlyFs);
+    assert_eq!(file.write_at(0, b"x").err(), err);
+    assert_eq!(file.resize(0).err(), err);
+    assert_eq!(file.set_metadata(&file.metadata()?).err(), err);
+    assert_eq!(root.create("new", FileType::File, 0o666).err(), err);
+    assert_eq!(root.create("new", FileType::Dir, 0o777).err(), err);
+    assert_eq!(root.link("link", &file).err(), err);
+    assert_eq!(root.unlink("file").err(), err);
+    assert_eq!(root.unlink("dir").err(), err);
+    assert_eq!(root.move_("file", &dir, "file").err(), err);
+    assert_eq!(root.move_("file", &root, "renamed").err(), err);
+    let inode = file.downcast_ref::<INodeImpl>().unwrap();
+    assert_eq!(inode.set_flags(INODE_FLAG_IMMUTABLE).err(), err);
+    assert_eq!(sefs.create_root("root").err(), err);
+    assert_eq!(sefs.sweep_orphans(false).err(), err);
+
+    // nothing changed
+    let mut buf = [0u8; 6];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"static");
+    assert_eq!(root.get_entry(3)?, "dir");
+    sefs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn reproducible_image() -> Result<()> {
+    static TIME: FixedTimeProvider = FixedTimeProvider(Timespec { sec: 1, nsec: 0 });
+    static RNG1: SeededRngProvider = SeededRngProvider::new(42);
+    static RNG2: SeededRngProvider = SeededRngProvider::new(42);
+    let build = |rng: &'static SeededRngProvider| -> Result<(TempDir, [u8; 16])> {
+        let dir = tempfile::tempdir().unwrap();
+        let sefs = SEFS::create(Box::new(StdStorage::new(dir.path())), &TIME, rng)?;
+        sefs.set_sealer(&ToySealer, rng);
+        let root = sefs.root_inode();
+        root.create("dir", FileType::Dir, 0o777)?
+            .create("file", FileType::File, 0o666)?
+            .write_at(0, b"hello")?;
+        _create_sealed(&root, "sealed")?.write_at(0, b"secret")?;
+        sefs.sync()?;
+        Ok((dir, sefs.uuid()))
+    };
+    let (dir1, uuid1) = build(&RNG1)?;
+    let (dir2, uuid2) = build(&RNG2)?;
+    assert_eq!(uuid1, uuid2);
+    assert_eq!(uuid1[6] >> 4, 4);
+    let storage1 = StdStorage::new(dir1.path());
+    let mut ids = storage1.list().unwrap();
+    ids.sort();
+    for id in ids {
+        let read = |dir: &TempDir| std::fs::read(dir.path().join(format!("{}", id))).unwrap();
+        assert_eq!(read(&dir1), read(&dir2), "file {} differs", id);
+    }
+    let (sefs, _dir) = _create_new_sefs();
+    assert_ne!(sefs.uuid(), uuid1);
+    Ok(())
+}
+
+/// Random numbers from nowhere, always failing
+struct NoRngProvider;
+
+impl RngProvider for NoRngProvider {
+    fn fill_bytes(&self, _buf: &mut [u8]) -> core::result::Result<(), DevError> {
+        Err(DevError::IOError)
+    }
+}
+
+#[test]
+fn rng_failure() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Box::new(StdStorage::new(dir.path()));
+    let err = SEFS::create(storage, &StdTimeProvider, &NoRngProvider).err();
+    assert_eq!(err, Some(FsError::DeviceError));
+
+    let (sefs, _dir) = _create_new_sefs();
+    sefs.set_sealer(&ToySealer, &NoRngProvider);
+    let file = _create_sealed(&sefs.root_inode(), "file")?;
+    assert_eq!(file.write_at(0, b"data"), Err(FsError::DeviceError));
+    assert_eq!(file.metadata()?.size, 0);
+    Ok(())
+}
+
+/// A toy sealer with a checksum as MAC, not secure
+struct ToySealer;
+
+impl ToySealer {
+    /// XOR `block` with a keystream of `nonce`, never zero
+    fn apply(nonce: u64, block: &mut [u8]) {
+        for (i, b) in block.iter_mut().enumerate() {
+            *b ^= (nonce.wrapping_mul(31).wrapping_add(i as u64) % 251 + 1) as u8;
+        }
+    }
+    /// Checksum of the plaintext `block` and where it is sealed
+    fn mac(file_id: usize, generation: u32, block_id: usize, nonce: u64, block: &[u8]) -> u64 {
+        let mut mac = file_id as u64 ^ (generation as u64) << 20 ^ (block_id as u64) << 40 ^ nonce;
+        for &b in block {
+            mac = mac.wrapping_mul(0x100_0000_01b3) ^ b as u64;
+        }
+        mac
+    }
+}
+
+impl seal::Sealer for ToySealer {
+    fn seal(
+        &self,
+        rng: &dyn RngProvider,
+        file_id: usize,
+        generation: u32,
+        block_id: usize,
+        block: &mut [u8; SEAL_BLOCK_SIZE],
+    ) -> core::result::Result<[u8; SEAL_TAG_SIZE], DevError> {
+        let mut nonce = [0u8; 8];
+        rng.fill_bytes(&mut nonce)?;
+        let nonce = u64::from_le_bytes(nonce);
+        let mac = Self::mac(file_id, generation, block_id, nonce, block);
+        Self::apply(nonce, block);
+        let mut tag = [0u8; SEAL_TAG_SIZE];
+        tag[..8].copy_from_slice(&nonce.to_le_bytes());
+        tag[8..16].copy_from_slice(&mac.to_le_bytes());
+        Ok(tag)
+    }
+    fn unseal(
+        &self,
+        file_id: usize,
+        generation: u32,
+        block_id: usize,
+        block: &mut [u8; SEAL_BLOCK_SIZE],
+        tag: &[u8; SEAL_TAG_SIZE],
+    ) -> bool {
+        let mut nonce = [0u8; 8];
+        let mut mac = [0u8; 8];
+        nonce.copy_from_slice(&tag[..8]);
+        mac.copy_from_slice(&tag[8..16]);
+        let nonce = u64::from_le_bytes(nonce);
+        Self::apply(nonce, block);
+        Self::mac(file_id, generation, block_id, nonce, block) == u64::from_le_bytes(mac)
+    }
+}
+
+/// Create `name` in `dir` and seal it
+fn _create_sealed(dir: &Arc<dyn INode>, name: &str) -> Result<Arc<dyn INode>> {
+    let file = dir.create(name, FileType::File, 0o666)?;
+    let file_impl = file.downcast_ref::<INodeImpl>().unwrap();
+    file_impl.set_flags(INODE_FLAG_SEALED)?;
+    Ok(file)
+}
+
+#[test]
+fn sealed_file() -> Result<()> {
+    let (sefs, dir) = _create_new_sefs();
+    sefs.set_sealer(&ToySealer, &StdRngProvider);
+    let root = sefs.root_inode();
+    let sealed = _create_sealed(&root, "sealed")?;
+    let plain = root.create("plain", FileType::File, 0o666)?;
+    let sealed_impl = sealed.downcast_ref::<INodeImpl>().unwrap();
+    let path = |inode: &Arc<dyn INode>| {
+        let id = *inode.extension::<usize>(EXT_FILE_ID).unwrap();
+        dir.path().join(format!("{}", id))
+    };
+    let raw = |inode: &Arc<dyn INode>| std::fs::read(path(inode)).unwrap();
+
+    sealed.write_at(4, b"secret")?;
+    plain.write_at(4, b"secret")?;
+    sealed.resize(12)?;
+    let mut buf = [0xffu8; 12];
+    assert_eq!(sealed.read_at(0, &mut buf)?, 12);
+    assert_eq!(&buf, b"\0\0\0\0secret\0\0");
+    assert_eq!(&raw(&plain)[4..], b"secret");
+    let sealed_raw = raw(&sealed);
+    assert_eq!(sealed_raw.len(), SEAL_TAG_SIZE + SEAL_BLOCK_SIZE);
+    let data = &sealed_raw[SEAL_TAG_SIZE..];
+    assert_ne!(&data[4..10], b"secret");
+    assert!(data[..4].iter().all(|&b| b != 0));
+
+    // the same data is sealed differently each time
+    sealed.write_at(4, b"secret")?;
+    assert_ne!(raw(&sealed), sealed_raw);
+    // modified data is detected
+    let mut modified = raw(&sealed);
+    modified[SEAL_TAG_SIZE + 4] ^= 1;
+    std::fs::write(path(&sealed), &modified).unwrap();
+    assert_eq!(sealed.read_at(0, &mut buf), Err(FsError::Corrupted));
+    assert_eq!(sealed.write_at(0, &[0u8; 12]), Err(FsError::Corrupted));
+    std::fs::write(path(&sealed), &sealed_raw).unwrap();
+
+    assert_eq!(sealed_impl.set_flags(0), Err(FsError::InvalidParam));
+    let plain_impl = plain.downcast_ref::<INodeImpl>().unwrap();
+    assert_eq!(
+        plain_impl.set_flags(INODE_FLAG_SEALED),
+        Err(FsError::InvalidParam)
+    );
+    sefs.sync()?;
+    drop((sealed, plain, root, sefs));
+
+    // can not access sealed files without the sealer
+    let sefs = SEFS::open(Box::new(StdStorage::new(dir.path())), &StdTimeProvider)?;
+    let root = sefs.root_inode();
+    let sealed = root.find("sealed")?;
+    assert_eq!(sealed.read_at(0, &mut buf), Err(FsError::NotSupported));
+    assert_eq!(root.find("plain")?.read_at(0, &mut buf)?, 10);
+    sefs.set_sealer(&ToySealer, &StdRngProvider);
+    assert_eq!(sealed.read_at(4, &mut buf)?, 8);
+    assert_eq!(&buf[..6], b"secret");
+    Ok(())
+}
+
+#[test]
+fn sealed_blocks() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    sefs.set_sealer(&ToySealer, &StdRngProvider);
+    let file = _create_sealed(&sefs.root_inode(), "file")?;
+    let data: Vec<u8> = (0..0x1800).map(|i| (i % 255 + 1) as u8).collect();
+    file.write_at(0x800, &data)?;
+    let mut buf = vec![0u8; 0x2000];
+    assert_eq!(file.read_at(0, &mut buf)?, 0x2000);
+    assert!(buf[..0x800].iter().all(|&b| b == 0));
+    assert_eq!(&buf[0x800..], &data[..]);
+
+    // shrinking and growing again leaves zeros
+    file.resize(0x1003)?;
+    file.resize(0x3000)?;
+    assert_eq!(file.read_at(0x1000, &mut buf)?, 0x2000);
+    assert_eq!(&buf[..3], &data[0x800..0x803]);
+    assert!(buf[3..].iter().all(|&b| b == 0));
+    // writing after a gap
+    file.write_at(0x5001, b"end")?;
+    assert_eq!(file.metadata()?.size, 0x5004);
+    let mut buf = vec![1u8; 0x2010];
+    assert_eq!(file.read_at(0x2fff, &mut buf)?, 0x2005);
+    assert!(buf[..0x2002].iter().all(|&b| b == 0));
+    assert_eq!(&buf[0x2002..0x2005], b"end");
+    Ok(())
+}
+
+#[test]
+fn sealed_file_id_reuse() -> Result<()> {
+    let (sefs, dir) = _create_new_sefs();
+    sefs.set_sealer(&ToySealer, &StdRngProvider);
+    let root = sefs.root_inode();
+    let generation = |inode: &Arc<dyn INode>| {
+        let inode = inode.downcast_ref::<INodeImpl>().unwrap();
+        inode.disk_inode.read().generation
+    };
+    let path = |id: usize| dir.path().join(format!("{}", id));
+
+    let sub1 = root.create("sub1", FileType::Dir, 0o777)?;
+    let old = _create_sealed(&sub1, "file")?;
+    old.write_at(0, b"old")?;
+    let id = *old.extension::<usize>(EXT_FILE_ID).unwrap();
+    let old_generation = generation(&old);
+    let old_raw = std::fs::read(path(id)).unwrap();
+    sub1.unlink("file")?;
+    drop(old);
+
+    // once sync gives back the pools, the lowest free id is taken by the next one
+    sefs.sync()?;
+    let new = _create_sealed(&root, "file")?;
+    new.write_at(0, b"new")?;
+    assert_eq!(*new.extension::<usize>(EXT_FILE_ID).unwrap(), id);
+    assert!(generation(&new) > old_generation);
+
+    // blocks of the removed file are not accepted
+    std::fs::write(path(id), &old_raw).unwrap();
+    let mut buf = [0u8; 3];
+    assert_eq!(new.read_at(0, &mut buf), Err(FsError::Corrupted));
+    Ok(())
+}
+
+#[test]
+fn sealed_generation_after_crash() -> Result<()> {
+    let (sefs, dir) = _create_new_sefs();
+    sefs.sync()?;
+    let root = sefs.root_inode();
+    let file = root.create("file", FileType::File, 0o666)?;
+    let generation = file
+        .downcast_ref::<INodeImpl>()
+        .unwrap()
+        .disk_inode
+        .read()
+        .generation;
+    // crash without syncing
+    core::mem::forget((file, root, sefs));
+
+    let sefs = SEFS::open(Box::new(StdStorage::new(dir.path())), &StdTimeProvider)?;
+    let file = sefs.root_inode().create("file", FileType::File, 0o666)?;
+    let file_impl = file.downcast_ref::<INodeImpl>().unwrap();
+    assert!(file_impl.disk_inode.read().generation > generation);
+    Ok(())
+}
+
+/// Cancelled from the `n`th check on
+struct CancelAfter(AtomicUsize);
+
+impl CancelToken for CancelAfter {
+    fn is_cancelled(&self) -> bool {
+        self.0.fetch_sub(1, Ordering::Relaxed) <= 1
+    }
+}
+
+#[test]
+fn sealed_cancelled_write() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    sefs.set_sealer(&ToySealer, &StdRngProvider);
+    let file = _create_sealed(&sefs.root_inode(), "file")?;
+    file.write_at(0, b"old")?;
+    let data = vec![1u8; 3 * SEAL_BLOCK_SIZE];
+    // cancelled after the first block is written
+    let token = CancelAfter(AtomicUsize::new(3));
+    assert_eq!(
+        file.write_at_cancellable(0, &data, Some(&token)),
+        Err(FsError::TimedOut)
+    );
+    // the file is back to its size and readable
+    assert_eq!(file.metadata()?.size, 3);
+    file.resize(SEAL_BLOCK_SIZE + 1)?;
+    let mut buf = vec![0xffu8; SEAL_BLOCK_SIZE + 1];
+    assert_eq!(file.read_at(0, &mut buf)?, SEAL_BLOCK_SIZE + 1);
+    assert!(buf[3..].iter().all(|&b| b == 0));
+    Ok(())
+}
+
+#[test]
+fn inode_id_pool() -> Result<()> {
+    let (sefs, dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let free = || sefs.info().bfree;
+    let before = free();
+    // pooled ids are counted as free
+    let sub = root.create("sub", FileType::Dir, 0o777)?;
+    assert_eq!(free(), before - 1);
+
+    let ids: Vec<usize> = (0..INODE_POOL_BATCH + 1)
+        .map(|i| {
+            let file = sub.create(&format!("{}", i), FileType::File, 0o666)?;
+            Ok(*file.extension::<usize>(EXT_FILE_ID).unwrap())
+        })
+        .collect::<Result<_>>()?;
+    assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(free(), before - (INODE_POOL_BATCH + 2));
+
+    // pooled ids are saved as free
+    sefs.sync()?;
+    let saved = SEFS::open(Box::new(StdStorage::new(dir.path())), &StdTimeProvider)?;
+    assert_eq!(saved.info().bfree, before - (INODE_POOL_BATCH + 2));
+    drop(saved);
+    sub.create("new", FileType::File, 0o666)?;
+    assert_eq!(free(), before - (INODE_POOL_BATCH + 3));
+    drop(sub);
+    assert_eq!(free(), before - (INODE_POOL_BATCH + 3));
+
+    // the pool outlives the directory handle, and is lent to other directories
+    let pooled = sefs.pooled_ids();
+    root.find("sub")?.create("again", FileType::File, 0o666)?;
+    assert_eq!(sefs.pooled_ids(), pooled - 1);
+    root.create("other", FileType::File, 0o666)?;
+    assert_eq!(sefs.pooled_ids(), pooled - 2);
+    assert_eq!(free(), before - (INODE_POOL_BATCH + 5));
+    // and given back by sync
+    sefs.sync()?;
+    assert_eq!(sefs.pooled_ids(), 0);
+    assert_eq!(free(), before - (INODE_POOL_BATCH + 5));
+    drop(root);
+    drop(sefs);
+
+    let sefs = SEFS::open(Box::new(StdStorage::new(dir.path())), &StdTimeProvider)?;
+    assert_eq!(sefs.info().bfree, before - (INODE_POOL_BATCH + 5));
+    assert!(sefs.sweep_orphans(true)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn move_dir_to_new_parent() -> Result<()> {
+    let (sefs, dir) = _create_new_sefs();
+    let root = sefs.root_inode();
+    let a = root.create("a", FileType::Dir, 0o777)?;
+    let b = root.create("b", FileType::Dir, 0o777)?;
+    let sub = a.create("sub", FileType::Dir, 0o777)?;
+    sub.create("file", FileType::File, 0o666)?;
+    let nlinks = |inode: &Arc<dyn INode>| inode.metadata().unwrap().nlinks;
+    assert_eq!((nlinks(&a), nlinks(&b)), (3, 2));
+
+    a.move_("sub", &b, "sub")?;
+    // '..' and the link counts follow the new parent
+    assert_eq!(sub.find("..")?.metadata()?.inode, b.metadata()?.inode);
+    assert_eq!((nlinks(&a), nlinks(&b)), (2, 3));
+    root.unlink("a")?;
+    drop((root, a, b, sub));
+    drop(sefs);
+
+    let sefs = SEFS::open(Box::new(StdStorage::new(dir.path())), &StdTimeProvider)?;
+    let b = sefs.root_inode().find("b")?;
+    let sub = b.find("sub")?;
+    assert_eq!(sub.find("..")?.metadata()?.inode, b.metadata()?.inode);
+    assert!(sub.find("..")?.find("sub")?.find("file").is_ok());
+    Ok(())
+}
+
+#[test]
+fn strictness() -> Result<()> {
+    for &strictness in &[Strictness::Relaxed, Strictness::Posix] {
+        let (sefs, _dir) = _create_new_sefs();
+        sefs.set_strictness(strictness);
+        assert_eq!(sefs.strictness(), strictness);
+        let posix = strictness == Strictness::Posix;
+        let root = sefs.root_inode();
+        let file1 = root.create("file1", FileType::File, 0o777)?;
+        let file2 = root.create("file2", FileType::File, 0o777)?;
+        root.create("dir1", FileType::Dir, 0o777)?;
+        let dir2 = root.create("dir2", FileType::Dir, 0o777)?;
+        file2.write_at(0, b"file2")?;
+
+        // rename-replace
+        root.link("link1", &file1)?;
+        let result = root.move_("file2", &root, "file1");
+        if posix {
+            result?;
+            // file1 now has the data of file2, link1 keeps the old file1
+            assert_eq!(file1.metadata()?.nlinks, 1);
+            root.link("link2", &root.find("file1")?)?;
+            assert_eq!(root.move_("file1", &root, "link2"), Ok(()));
+            assert!(root.find("file1").is_ok());
+            assert_eq!(root.move_("dir1", &root, "file1"), Err(FsError::NotDir));
+            assert_eq!(root.move_("file1", &root, "dir1"), Err(FsError::IsDir));
+            dir2.create("x", FileType::File, 0o777)?;
+            assert_eq!(root.move_("dir1", &root, "dir2"), Err(FsError::DirNotEmpty));
+            dir2.unlink("x")?;
+            root.move_("dir1", &root, "dir2")?;
+            let mut buf = [0u8; 5];
+            root.find("file1")?.read_at(0, &mut buf)?;
+            assert_eq!(&buf, b"file2");
+            assert_eq!(root.list()?, [".", "..", "file1", "link1", "link2", "dir2"]);
+
+            // a failed replace leaves both entries
+            let file3 = root.create("file3", FileType::File, 0o777)?;
+            let file3_impl = file3.downcast_ref::<INodeImpl>().unwrap();
+            file3_impl.set_flags(INODE_FLAG_IMMUTABLE)?;
+            assert_eq!(
+                root.move_("link1", &root, "file3"),
+                Err(FsError::OperationNotPermitted)
+            );
+            file3_impl.set_flags(0)?;
+            assert!(root.find("link1").is_ok());
+            assert!(root.find("file3").is_ok());
+            root.unlink("file3")?;
+        } else {
+            assert_eq!(result, Err(FsError::EntryExist));
+            assert_eq!(
+                root.move_("file1", &root, "link1"),
+                Err(FsError::EntryExist)
+            );
+        }
+
+        // moving a directory into itself
+        let dir = root.create("dir", FileType::Dir, 0o777)?;
+        let sub = dir.create("sub", FileType::Dir, 0o777)?;
+        if posix {
+            assert_eq!(root.move_("dir", &sub, "dir"), Err(FsError::InvalidParam));
+            assert_eq!(root.move_("dir", &dir, "dir"), Err(FsError::InvalidParam));
+            // a broken '..' on the way up is an error
+            let sub_impl = sub.downcast_ref::<INodeImpl>().unwrap();
+            for &(id, name) in &[(dir.metadata()?.inode, "xx"), (100_000, "..")] {
+                let entry = DiskEntry {
+                    id: id as u32,
+                    name: Str256::from(name),
+                };
+                sub_impl.file.write_direntry(1, &entry)?;
+                assert_eq!(root.move_("dir2", &sub, "dir2"), Err(FsError::Corrupted));
+            }
+            sub_impl.dirent_set_parent(dir.metadata()?.inode)?;
+        }
+        dir.move_("sub", &root, "sub")?;
+
+        // timestamps
+        let file = root.create("file", FileType::File, 0o777)?;
+        let mut metadata = file.metadata()?;
+        metadata.atime = Timespec { sec: 0, nsec: 0 };
+        metadata.mtime = Timespec { sec: 0, nsec: 0 };
+        metadata.ctime = Timespec { sec: 0, nsec: 0 };
+        file.set_metadata(&metadata)?;
+        root.set_metadata(&metadata)?;
+        file.write_at(0, b"data")?;
+        let metadata = file.metadata()?;
+        assert_eq!(metadata.mtime.sec != 0, posix);
+        assert_eq!(metadata.ctime.sec != 0, posix);
+        assert_eq!(metadata.atime.sec, 0);
+        file.read_at(0, &mut [0u8; 4])?;
+        assert_eq!(file.metadata()?.atime.sec != 0, posix);
+        root.unlink("dir")?;
+        assert_eq!(root.metadata()?.mtime.sec != 0, posix);
+
+        // unlink while open
+        root.unlink("file")?;
+        assert_eq!(file.metadata()?.nlinks, 0);
+        let mut buf = [0u8; 4];
+        assert_eq!(file.read_at(0, &mut buf)?, 4);
+        assert_eq!(&buf, b"data");
+        assert_eq!(root.find("file").err(), Some(FsError::EntryNotFound));
+    }
+    Ok(())
+}
+
+#[test]
+fn cancel_io() -> Result<()> {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    let (sefs, _dir) = _create_new_sefs();
+    let file = sefs.root_inode().create("file", FileType::File, 0o777)?;
+    let token = AtomicBool::new(false);
+    assert_eq!(file.write_at_cancellable(0, b"data", Some(&token))?, 4);
+    let mut buf = [0u8; 4];
+    assert_eq!(file.read_at_cancellable(0, &mut buf, Some(&token))?, 4);
+    assert_eq!(&buf, b"data");
+
+    token.store(true, Ordering::Relaxed);
+    assert_eq!(
+        file.read_at_cancellable(0, &mut buf, Some(&token)),
+        Err(FsError::TimedOut)
+    );
+    assert_eq!(
+        file.write_at_cancellable(0, b"lost", Some(&token)),
+        Err(FsError::TimedOut)
+    );
+    file.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"data");
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn serde_round_trip() -> Result<()> {
+    let (sefs, _dir) = _create_new_sefs();
+    let file = sefs.root_inode().create("file", FileType::File, 0o777)?;
+    file.write_at(0, b"data")?;
+    let file = file.downcast_ref::<INodeImpl>().unwrap();
+
+    let disk_inode = file.disk_inode.read();
+    let json = serde_json::to_string(&**disk_inode).unwrap();
+    let back: DiskINode = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", back), format!("{:?}", **disk_inode));
+
+    let super_block = sefs.super_block.read();
+    let json = serde_json::to_string(&**super_block).unwrap();
+    let back: SuperBlock = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", back), format!("{:?}", **super_block));
+    Ok(())
+}
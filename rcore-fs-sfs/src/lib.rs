@@ -16,9 +16,9 @@ use core::fmt::{Debug, Error, Formatter};
 use core::mem::MaybeUninit;
 
 use bitvec::prelude::*;
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
-use rcore_fs::dev::Device;
+use rcore_fs::dev::{CancelToken, DevError, Device};
 use rcore_fs::dirty::Dirty;
 use rcore_fs::util::*;
 use rcore_fs::vfs::{self, FileSystem, FsError, INode, MMapArea, Metadata};
@@ -31,16 +31,38 @@ mod tests;
 
 trait DeviceExt: Device {
     fn read_block(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        self.read_block_cancellable(id, offset, buf, None)
+    }
+    fn write_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        self.write_block_cancellable(id, offset, buf, None)
+    }
+    /// Same as `read_block`, but give up with `TimedOut` when `token` is cancelled
+    fn read_block_cancellable(
+        &self,
+        id: BlockId,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<()> {
         debug_assert!(offset + buf.len() <= BLKSIZE);
-        match self.read_at(id * BLKSIZE + offset, buf) {
+        match self.read_at_cancellable(id * BLKSIZE + offset, buf, token) {
             Ok(len) if len == buf.len() => Ok(()),
+            Err(DevError::TimedOut) => Err(FsError::TimedOut),
             _ => panic!("cannot read block {} offset {} from device", id, offset),
         }
     }
-    fn write_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+    /// Same as `write_block`, but give up with `TimedOut` when `token` is cancelled
+    fn write_block_cancellable(
+        &self,
+        id: BlockId,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<()> {
         debug_assert!(offset + buf.len() <= BLKSIZE);
-        match self.write_at(id * BLKSIZE + offset, buf) {
+        match self.write_at_cancellable(id * BLKSIZE + offset, buf, token) {
             Ok(len) if len == buf.len() => Ok(()),
+            Err(DevError::TimedOut) => Err(FsError::TimedOut),
             _ => panic!("cannot write block {} offset {} to device", id, offset),
         }
     }
@@ -60,6 +82,8 @@ pub struct INodeImpl {
     id: INodeId,
     /// On-disk INode
     disk_inode: RwLock<Dirty<DiskINode>>,
+    /// Held by `append`, so appends do not overwrite each other
+    append_lock: Mutex<()>,
     /// Reference to SFS, used by almost all operations
     fs: Arc<SimpleFileSystem>,
     /// Char/block device id (major, minor)
@@ -186,11 +210,11 @@ impl INodeImpl {
     }
     fn read_direntry(&self, id: usize) -> vfs::Result<DiskEntry> {
         let mut direntry: DiskEntry = unsafe { MaybeUninit::uninit().assume_init() };
-        self._read_at(DIRENT_SIZE * id, direntry.as_buf_mut())?;
+        self._read_at(DIRENT_SIZE * id, direntry.as_buf_mut(), None)?;
         Ok(direntry)
     }
     fn write_direntry(&self, id: usize, direntry: &DiskEntry) -> vfs::Result<()> {
-        self._write_at(DIRENT_SIZE * id, direntry.as_buf())?;
+        self._write_at(DIRENT_SIZE * id, direntry.as_buf(), None)?;
         Ok(())
     }
     fn append_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
@@ -338,19 +362,35 @@ impl INodeImpl {
         Ok(buf_offset)
     }
     /// Read content, no matter what type it is
-    fn _read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+    fn _read_at(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<usize> {
         self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.read_block(
+            device.read_block_cancellable(
                 range.block,
                 range.begin,
                 &mut buf[offset..offset + range.len()],
+                token,
             )
         })
     }
     /// Write content, no matter what type it is
-    fn _write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+    fn _write_at(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<usize> {
         self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.write_block(range.block, range.begin, &buf[offset..offset + range.len()])
+            device.write_block_cancellable(
+                range.block,
+                range.begin,
+                &buf[offset..offset + range.len()],
+                token,
+            )
         })
     }
     /// Clean content, no matter what type it is
@@ -370,6 +410,7 @@ impl INodeImpl {
     }
 
     pub fn link_inodeimpl(&self, name: &str, other: &Arc<INodeImpl>) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -394,7 +435,7 @@ impl INodeImpl {
         let disk_inode = self.disk_inode.write();
         let old_size = disk_inode.size as usize;
         self._resize(old_size + BLKSIZE)?;
-        self._write_at(old_size, entry.as_buf()).unwrap();
+        self._write_at(old_size, entry.as_buf(), None).unwrap();
         child.nlinks_inc();
         Ok(())
     }
@@ -402,41 +443,64 @@ impl INodeImpl {
 
 impl vfs::INode for INodeImpl {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        self.read_at_cancellable(offset, buf, None)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        self.write_at_cancellable(offset, buf, None)
+    }
+    fn read_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<usize> {
         match self.disk_inode.read().type_ {
-            FileType::File => self._read_at(offset, buf),
-            FileType::SymLink => self._read_at(offset, buf),
+            FileType::File => self._read_at(offset, buf, token),
+            FileType::SymLink => self._read_at(offset, buf, token),
             FileType::CharDevice => {
                 let device_inodes = self.fs.device_inodes.read();
                 let device_inode = device_inodes.get(&self.device_inode_id);
                 match device_inode {
-                    Some(device) => device.read_at(offset, buf),
+                    Some(device) => device.read_at_cancellable(offset, buf, token),
                     None => Err(FsError::DeviceError),
                 }
             }
             _ => Err(FsError::NotFile),
         }
     }
-    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+    fn write_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> vfs::Result<usize> {
         let DiskINode { type_, size, .. } = **self.disk_inode.read();
         match type_ {
             FileType::File | FileType::SymLink => {
+                self.fs.check_writable()?;
                 let end_offset = offset + buf.len();
                 if (size as usize) < end_offset {
                     self._resize(end_offset)?;
                 }
-                self._write_at(offset, buf)
+                self._write_at(offset, buf, token)
             }
             FileType::CharDevice => {
                 let device_inodes = self.fs.device_inodes.write();
                 let device_inode = device_inodes.get(&self.device_inode_id);
                 match device_inode {
-                    Some(device) => device.write_at(offset, buf),
+                    Some(device) => device.write_at_cancellable(offset, buf, token),
                     None => Err(FsError::DeviceError),
                 }
             }
             _ => Err(FsError::NotFile),
         }
     }
+    fn append(&self, buf: &[u8]) -> vfs::Result<(usize, usize)> {
+        let _lock = self.append_lock.lock();
+        let offset = self.disk_inode.read().size as usize;
+        let len = self.write_at(offset, buf)?;
+        Ok((offset, len))
+    }
     fn poll(&self) -> vfs::Result<vfs::PollStatus> {
         Ok(vfs::PollStatus {
             read: true,
@@ -471,6 +535,7 @@ impl vfs::INode for INodeImpl {
         })
     }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let mut disk_inode = self.disk_inode.write();
         disk_inode.atime = metadata.atime;
         disk_inode.mtime = metadata.mtime;
@@ -482,7 +547,7 @@ impl vfs::INode for INodeImpl {
         if disk_inode.dirty() {
             self.fs
                 .device
-                .write_block(self.id, 0, disk_inode.as_buf())?;
+                .write_block(self.id, 0, &disk_inode.to_disk())?;
             disk_inode.sync();
         }
         Ok(())
@@ -491,6 +556,7 @@ impl vfs::INode for INodeImpl {
         self.sync_all()
     }
     fn resize(&self, len: usize) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         if self.disk_inode.read().type_ != FileType::File
             && self.disk_inode.read().type_ != FileType::SymLink
         {
@@ -505,6 +571,7 @@ impl vfs::INode for INodeImpl {
         _mode: u32,
         data: usize,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        self.fs.check_writable()?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -542,6 +609,7 @@ impl vfs::INode for INodeImpl {
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -569,6 +637,7 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -605,6 +674,7 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        self.fs.check_writable()?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -750,6 +820,8 @@ pub struct SimpleFileSystem {
     inodes: RwLock<BTreeMap<INodeId, Weak<INodeImpl>>>,
     /// device
     device: Arc<dyn Device>,
+    /// Reject all changes, set when the device is read-only
+    read_only: bool,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SimpleFileSystem>,
     /// device inode
@@ -776,6 +848,7 @@ impl SimpleFileSystem {
             super_block: RwLock::new(Dirty::new(super_block)),
             free_map: RwLock::new(Dirty::new(BitVec::from(freemap_disk.as_slice()))),
             inodes: RwLock::new(BTreeMap::new()),
+            read_only: device.is_read_only(),
             device,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
@@ -787,6 +860,9 @@ impl SimpleFileSystem {
         let blocks = (space + BLKSIZE - 1) / BLKSIZE;
         let freemap_blocks = (space + BLKBITS * BLKSIZE - 1) / BLKBITS / BLKSIZE;
         assert!(blocks >= 16, "space too small");
+        if device.is_read_only() {
+            return Err(FsError::ReadOnlyFs);
+        }
 
         let super_block = SuperBlock {
             magic: MAGIC,
@@ -809,6 +885,7 @@ impl SimpleFileSystem {
             free_map: RwLock::new(Dirty::new_dirty(free_map)),
             inodes: RwLock::new(BTreeMap::new()),
             device,
+            read_only: false,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
         }
@@ -837,6 +914,16 @@ impl SimpleFileSystem {
         unsafe { Arc::from_raw(ptr) }
     }
 
+    /// Whether all changes are rejected, because the device is read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    fn check_writable(&self) -> vfs::Result<()> {
+        match self.read_only {
+            true => Err(FsError::ReadOnlyFs),
+            false => Ok(()),
+        }
+    }
     /// Allocate a block, return block id
     fn alloc_block(&self) -> Option<usize> {
         let mut free_map = self.free_map.write();
@@ -877,6 +964,7 @@ impl SimpleFileSystem {
         let inode = Arc::new(INodeImpl {
             id,
             disk_inode: RwLock::new(disk_inode),
+            append_lock: Mutex::new(()),
             fs: self.self_ptr.upgrade().unwrap(),
             device_inode_id,
         });
@@ -921,6 +1009,7 @@ impl SimpleFileSystem {
     }
     /// Create a new INode chardevice
     pub fn new_inode_chardevice(&self, device_inode_id: usize) -> vfs::Result<Arc<INodeImpl>> {
+        self.check_writable()?;
         let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
         let disk_inode = Dirty::new_dirty(DiskINode::new_chardevice(device_inode_id));
         let new_inode = self._new_inode(id, disk_inode);
@@ -4,14 +4,17 @@ use crate::vfs;
 use alloc::str;
 
 use core::fmt::{Debug, Error, Formatter};
-use core::mem::{size_of, size_of_val};
+use core::mem::{size_of, size_of_val, MaybeUninit};
 use core::slice;
 use rcore_fs::vfs::Timespec;
+#[cfg(feature = "std")]
+use serde::{Deserialize, Serialize};
 use static_assertions::const_assert;
 
 /// On-disk superblock
 #[repr(C)]
 #[derive(Debug)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub struct SuperBlock {
     /// magic number, should be SFS_MAGIC
     pub magic: u32,
@@ -28,6 +31,7 @@ pub struct SuperBlock {
 /// inode (on disk)
 #[repr(C)]
 #[derive(Debug)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub struct DiskINode {
     /// size of the file (in bytes)
     /// undefined in dir (256 * #entries ?)
@@ -83,6 +87,7 @@ pub struct DiskEntry {
 pub struct Str256(pub [u8; 256]);
 
 #[repr(C)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub struct Str32(pub [u8; 32]);
 
 impl AsRef<str> for Str256 {
@@ -194,6 +199,28 @@ impl DiskINode {
             ctime: Timespec { sec: 0, nsec: 0 },
         }
     }
+    /// Bytes to store on disk, with the padding zeroed
+    /// so that the same inode is always stored as the same bytes.
+    pub fn to_disk(&self) -> [u8; size_of::<DiskINode>()] {
+        let mut disk: DiskINode = unsafe { MaybeUninit::zeroed().assume_init() };
+        disk.size = self.size;
+        disk.type_ = self.type_;
+        disk.nlinks = self.nlinks;
+        disk.blocks = self.blocks;
+        disk.direct = self.direct;
+        disk.indirect = self.indirect;
+        disk.db_indirect = self.db_indirect;
+        disk.device_inode_id = self.device_inode_id;
+        disk.atime.sec = self.atime.sec;
+        disk.atime.nsec = self.atime.nsec;
+        disk.mtime.sec = self.mtime.sec;
+        disk.mtime.nsec = self.mtime.nsec;
+        disk.ctime.sec = self.ctime.sec;
+        disk.ctime.nsec = self.ctime.nsec;
+        let mut buf = [0u8; size_of::<DiskINode>()];
+        buf.copy_from_slice(disk.as_buf());
+        buf
+    }
 }
 
 /// Convert structs to [u8] slice
@@ -264,6 +291,7 @@ pub const MAX_NBLOCK_DOUBLE_INDIRECT: usize = NDIRECT + BLK_NENTRY + BLK_NENTRY
 /// file types
 #[repr(u16)]
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub enum FileType {
     Invalid = 0,
     File = 1,
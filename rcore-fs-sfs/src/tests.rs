@@ -1,7 +1,8 @@
 extern crate std;
 
 use crate::*;
-use rcore_fs::vfs::{FileSystem, FileType, Metadata, Result, Timespec};
+use rcore_fs::file::{File, OpenFlags};
+use rcore_fs::vfs::{FileSystem, FileType, FsError, Metadata, Result, Timespec};
 use std::fs::{self, OpenOptions};
 
 use std::sync::Arc;
@@ -566,3 +567,230 @@ fn create_then_get_entry() -> Result<()> {
     sfs.sync()?;
     Ok(())
 }
+
+#[test]
+fn open_flags() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+
+    assert_eq!(
+        root.open("dir/file", OpenFlags::RDONLY, 0o666).err(),
+        Some(FsError::EntryNotFound)
+    );
+    let mut file = root.open("dir/file", OpenFlags::WRONLY | OpenFlags::CREATE, 0o666)?;
+    assert_eq!(file.write(b"hello")?, 5);
+    assert_eq!(file.read(&mut [0u8; 5]), Err(FsError::BadFd));
+    assert_eq!(
+        root.open(
+            "dir/file",
+            OpenFlags::RDWR | OpenFlags::CREATE | OpenFlags::EXCL,
+            0o666
+        )
+        .err(),
+        Some(FsError::EntryExist)
+    );
+
+    let mut file = dir.open("file", OpenFlags::WRONLY | OpenFlags::APPEND, 0)?;
+    file.write(b" world")?;
+    let mut file = root.open("/dir/file", OpenFlags::RDONLY, 0)?;
+    let mut buf = [0u8; 16];
+    assert_eq!(file.read(&mut buf)?, 11);
+    assert_eq!(&buf[..11], b"hello world");
+    assert_eq!(file.write(b"x"), Err(FsError::BadFd));
+
+    root.open("dir/file", OpenFlags::WRONLY | OpenFlags::TRUNC, 0)?;
+    assert_eq!(dir.find("file")?.metadata()?.size, 0);
+
+    // no access mode is given
+    assert_eq!(
+        root.open(
+            "dir/new",
+            OpenFlags::WRONLY | OpenFlags::RDWR | OpenFlags::CREATE,
+            0o666
+        )
+        .err(),
+        Some(FsError::InvalidParam)
+    );
+    assert_eq!(dir.find("new").err(), Some(FsError::EntryNotFound));
+    let mut file = File::new(dir.find("file")?, false, false);
+    assert_eq!(file.read(&mut [0u8; 1]), Err(FsError::BadFd));
+    assert_eq!(file.write(b"x"), Err(FsError::BadFd));
+
+    // appends from many handles do not overwrite each other
+    let threads: Vec<_> = (0..4u8)
+        .map(|i| {
+            let mut file = dir.open("file", OpenFlags::WRONLY | OpenFlags::APPEND, 0)?;
+            Ok(std::thread::spawn(move || {
+                for _ in 0..50 {
+                    file.write(&[i; 10]).unwrap();
+                }
+            }))
+        })
+        .collect::<Result<_>>()?;
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let mut data = vec![0u8; 2000];
+    assert_eq!(dir.find("file")?.read_at(0, &mut data)?, 2000);
+    for record in data.chunks(10) {
+        assert!(record.iter().all(|&b| b == record[0]));
+    }
+
+    assert_eq!(
+        root.open("dir/file", OpenFlags::DIRECTORY, 0).err(),
+        Some(FsError::NotDir)
+    );
+    assert_eq!(
+        root.open("dir", OpenFlags::RDWR, 0).err(),
+        Some(FsError::IsDir)
+    );
+    assert!(root.open("dir/", OpenFlags::DIRECTORY, 0).is_ok());
+
+    let link = root.create("link", FileType::SymLink, 0o777)?;
+    link.write_at(0, b"dir/file")?;
+    assert!(root.open("link", OpenFlags::RDONLY, 0).is_ok());
+    assert_eq!(
+        root.open("link", OpenFlags::NOFOLLOW, 0).err(),
+        Some(FsError::SymLoop)
+    );
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn open_static_image() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let file = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(file.clone(), 32 * 4096)?;
+    sfs.root_inode()
+        .create("file", FileType::File, 0o777)?
+        .write_at(0, b"static")?;
+    sfs.sync()?;
+    drop(sfs);
+
+    let mut image = Vec::new();
+    {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut image).unwrap();
+    }
+    let image: &'static [u8] = Box::leak(image.into_boxed_slice());
+    let sfs = SimpleFileSystem::open(Arc::new(image))?;
+    assert!(sfs.is_read_only());
+    let root = sfs.root_inode();
+    let file = root.lookup("file")?;
+    let mut buf = [0u8; 6];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"static");
+
+    // changes are rejected before anything is modified
+    let err = Err(FsError::ReadOnlyFs);
+    assert_eq!(file.write_at(0, b"x"), Err(FsError::ReadOnlyFs));
+    assert_eq!(file.resize(0), err);
+    assert_eq!(file.set_metadata(&file.metadata()?), err);
+    assert_eq!(
+        root.create("new", FileType::File, 0o777).err(),
+        Some(FsError::ReadOnlyFs)
+    );
+    assert_eq!(root.unlink("file"), err);
+    assert_eq!(root.move_("file", &root, "moved"), err);
+    assert_eq!(
+        SimpleFileSystem::create(Arc::new(image), 32 * 4096).err(),
+        Some(FsError::ReadOnlyFs)
+    );
+    drop((file, root));
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn cancel_io() -> Result<()> {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    let sfs = _create_new_sfs();
+    let file = sfs.root_inode().create("file", FileType::File, 0o777)?;
+    let token = AtomicBool::new(false);
+    assert_eq!(file.write_at_cancellable(0, b"data", Some(&token))?, 4);
+    let mut buf = [0u8; 4];
+    assert_eq!(file.read_at_cancellable(0, &mut buf, Some(&token))?, 4);
+    assert_eq!(&buf, b"data");
+
+    token.store(true, Ordering::Relaxed);
+    assert_eq!(
+        file.read_at_cancellable(0, &mut buf, Some(&token)),
+        Err(FsError::TimedOut)
+    );
+    Ok(())
+}
+
+#[test]
+fn disk_inode_padding_is_zeroed() {
+    let mut inode = DiskINode::new_file();
+    inode.size = u32::MAX;
+    inode.nlinks = u16::MAX;
+    inode.blocks = u32::MAX;
+    inode.direct = [u32::MAX; NDIRECT];
+    inode.indirect = u32::MAX;
+    inode.db_indirect = u32::MAX;
+    inode.device_inode_id = usize::MAX;
+    inode.atime = Timespec { sec: -1, nsec: -1 };
+    inode.mtime = Timespec { sec: -1, nsec: -1 };
+    inode.ctime = Timespec { sec: -1, nsec: -1 };
+    let disk = inode.to_disk();
+
+    // bytes of each field in `disk`
+    let base = &inode as *const _ as usize;
+    macro_rules! bytes {
+        ($field:expr) => {{
+            let offset = &$field as *const _ as usize - base;
+            offset..offset + core::mem::size_of_val(&$field)
+        }};
+    }
+    let type_ = bytes!(inode.type_);
+    let fields = [
+        bytes!(inode.size),
+        bytes!(inode.nlinks),
+        bytes!(inode.blocks),
+        bytes!(inode.direct),
+        bytes!(inode.indirect),
+        bytes!(inode.db_indirect),
+        bytes!(inode.device_inode_id),
+        bytes!(inode.atime.sec),
+        bytes!(inode.atime.nsec),
+        bytes!(inode.mtime.sec),
+        bytes!(inode.mtime.nsec),
+        bytes!(inode.ctime.sec),
+        bytes!(inode.ctime.nsec),
+    ];
+    for (i, &byte) in disk.iter().enumerate() {
+        if type_.contains(&i) {
+            continue;
+        }
+        match fields.iter().any(|field| field.contains(&i)) {
+            true => assert_eq!(byte, 0xff, "field byte {}", i),
+            false => assert_eq!(byte, 0, "padding byte {}", i),
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn serde_round_trip() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let file = sfs.root_inode().create("file", FileType::File, 0o777)?;
+    file.write_at(0, b"data")?;
+    let file = file.downcast_ref::<INodeImpl>().unwrap();
+
+    let disk_inode = file.disk_inode.read();
+    let json = serde_json::to_string(&**disk_inode).unwrap();
+    let back: DiskINode = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", back), format!("{:?}", **disk_inode));
+
+    let super_block = sfs.super_block.read();
+    let json = serde_json::to_string(&**super_block).unwrap();
+    let back: SuperBlock = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", back), format!("{:?}", **super_block));
+    Ok(())
+}
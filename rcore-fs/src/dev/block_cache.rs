@@ -1,12 +1,15 @@
 //! A naive LRU cache layer for `BlockDevice`
+use super::budget::{MemoryBudget, Reclaim};
 use super::*;
-use alloc::{vec, vec::Vec};
+use alloc::{sync::Arc, vec, vec::Vec};
 use spin::{Mutex, MutexGuard};
 
 pub struct BlockCache<T: BlockDevice> {
     device: T,
     bufs: Vec<Mutex<Buf>>,
     lru: Mutex<LRU>,
+    /// If set, buffers are allocated on demand and charged to it
+    budget: Option<Arc<MemoryBudget>>,
 }
 
 struct Buf {
@@ -14,6 +17,16 @@ struct Buf {
     data: Vec<u8>,
 }
 
+impl Buf {
+    /// Unused and without memory, only with a budget
+    fn is_unallocated(&self) -> bool {
+        match self.status {
+            BufStatus::Unused => self.data.is_empty(),
+            _ => false,
+        }
+    }
+}
+
 enum BufStatus {
     /// buffer is unused
     Unused,
@@ -33,22 +46,57 @@ impl<T: BlockDevice> BlockCache<T> {
             })
         });
         let lru = Mutex::new(LRU::new(capacity));
-        BlockCache { device, bufs, lru }
+        BlockCache {
+            device,
+            bufs,
+            lru,
+            budget: None,
+        }
+    }
+
+    /// Create a cache of at most `capacity` blocks whose memory is charged to `budget`.
+    ///
+    /// Buffers are allocated when first used, and freed when other caches
+    /// sharing the budget need memory.
+    pub fn with_budget(device: T, capacity: usize, budget: Arc<MemoryBudget>) -> Arc<Self>
+    where
+        T: 'static,
+    {
+        let mut bufs = Vec::new();
+        bufs.resize_with(capacity, || {
+            Mutex::new(Buf {
+                status: BufStatus::Unused,
+                data: Vec::new(),
+            })
+        });
+        let lru = Mutex::new(LRU::new(capacity));
+        let cache = Arc::new(BlockCache {
+            device,
+            bufs,
+            lru,
+            budget: Some(budget.clone()),
+        });
+        let reclaim: Arc<dyn Reclaim> = cache.clone();
+        budget.register(Arc::downgrade(&reclaim));
+        cache
     }
 
-    /// Get a buffer for `block_id` with any status
-    fn get_buf(&self, block_id: BlockId) -> MutexGuard<Buf> {
-        let (i, buf) = self._get_buf(block_id);
+    /// Get a buffer for `block_id` with any status.
+    ///
+    /// Return `None` if the block is not cached and the budget has no memory
+    /// left for it, then the device should be accessed directly.
+    fn get_buf(&self, block_id: BlockId) -> Option<MutexGuard<Buf>> {
+        let (i, buf) = self._get_buf(block_id)?;
         self.lru.lock().visit(i);
-        buf
+        Some(buf)
     }
 
-    fn _get_buf(&self, block_id: BlockId) -> (usize, MutexGuard<Buf>) {
+    fn _get_buf(&self, block_id: BlockId) -> Option<(usize, MutexGuard<Buf>)> {
         for (i, buf) in self.bufs.iter().enumerate() {
             if let Some(lock) = buf.try_lock() {
                 match lock.status {
-                    BufStatus::Valid(id) if id == block_id => return (i, lock),
-                    BufStatus::Dirty(id) if id == block_id => return (i, lock),
+                    BufStatus::Valid(id) if id == block_id => return Some((i, lock)),
+                    BufStatus::Dirty(id) if id == block_id => return Some((i, lock)),
                     _ => {}
                 }
             }
@@ -57,19 +105,50 @@ impl<T: BlockDevice> BlockCache<T> {
     }
 
     /// Get an unused buffer
-    fn get_unused(&self) -> (usize, MutexGuard<Buf>) {
+    fn get_unused(&self) -> Option<(usize, MutexGuard<Buf>)> {
+        // prefer a buffer which still holds memory
         for (i, buf) in self.bufs.iter().enumerate() {
             if let Some(lock) = buf.try_lock() {
                 if let BufStatus::Unused = lock.status {
-                    return (i, lock);
+                    if !lock.data.is_empty() {
+                        return Some((i, lock));
+                    }
                 }
             }
         }
+        // allocate a new buffer if the budget allows.
+        // No buffer is locked while charging, as reclaiming may come back to this cache.
+        if let Some(budget) = &self.budget {
+            let unallocated = |buf: &Mutex<Buf>| match buf.try_lock() {
+                Some(lock) => lock.is_unallocated(),
+                None => false,
+            };
+            if self.bufs.iter().any(unallocated) && budget.charge(Self::block_size()) {
+                for (i, buf) in self.bufs.iter().enumerate() {
+                    if let Some(mut lock) = buf.try_lock() {
+                        if lock.is_unallocated() {
+                            lock.data = vec![0; Self::block_size()];
+                            return Some((i, lock));
+                        }
+                    }
+                }
+                // taken by others in the meantime
+                budget.uncharge(Self::block_size());
+            }
+        }
         let victim_id = self.lru.lock().victim();
         let mut victim = self.bufs[victim_id].lock();
         self.write_back(&mut victim).expect("failed to write back");
         victim.status = BufStatus::Unused;
-        (victim_id, victim)
+        if victim.data.is_empty() {
+            // the victim was reclaimed, and there is no memory for it
+            return None;
+        }
+        Some((victim_id, victim))
+    }
+
+    fn block_size() -> usize {
+        1 << T::BLOCK_SIZE_LOG2 as usize
     }
 
     /// Write back data if buffer is dirty
@@ -85,6 +164,34 @@ impl<T: BlockDevice> BlockCache<T> {
 impl<T: BlockDevice> Drop for BlockCache<T> {
     fn drop(&mut self) {
         BlockDevice::sync(self).expect("failed to sync");
+        if let Some(budget) = &self.budget {
+            let allocated = self.bufs.iter().filter(|buf| !buf.lock().data.is_empty());
+            budget.uncharge(allocated.count() * Self::block_size());
+        }
+    }
+}
+
+impl<T: BlockDevice> Reclaim for BlockCache<T> {
+    /// Free clean or dirty buffers which are not in use
+    fn reclaim(&self, bytes: usize) -> usize {
+        let mut freed = 0;
+        for buf in self.bufs.iter() {
+            if freed >= bytes {
+                break;
+            }
+            if let Some(mut buf) = buf.try_lock() {
+                if buf.data.is_empty() || self.write_back(&mut buf).is_err() {
+                    continue;
+                }
+                buf.status = BufStatus::Unused;
+                buf.data = Vec::new();
+                freed += Self::block_size();
+            }
+        }
+        if let Some(budget) = &self.budget {
+            budget.uncharge(freed);
+        }
+        freed
     }
 }
 
@@ -92,7 +199,10 @@ impl<T: BlockDevice> BlockDevice for BlockCache<T> {
     const BLOCK_SIZE_LOG2: u8 = T::BLOCK_SIZE_LOG2;
 
     fn read_at(&self, block_id: BlockId, buffer: &mut [u8]) -> Result<()> {
-        let mut buf = self.get_buf(block_id);
+        let mut buf = match self.get_buf(block_id) {
+            Some(buf) => buf,
+            None => return self.device.read_at(block_id, buffer),
+        };
         match buf.status {
             BufStatus::Unused => {
                 // read from device
@@ -107,7 +217,10 @@ impl<T: BlockDevice> BlockDevice for BlockCache<T> {
     }
 
     fn write_at(&self, block_id: BlockId, buffer: &[u8]) -> Result<()> {
-        let mut buf = self.get_buf(block_id);
+        let mut buf = match self.get_buf(block_id) {
+            Some(buf) => buf,
+            None => return self.device.write_at(block_id, buffer),
+        };
         buf.status = BufStatus::Dirty(block_id);
         let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
         buf.data.copy_from_slice(&buffer[..len]);
@@ -162,3 +275,114 @@ impl LRU {
         self.prev[head] = id;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// 8 blocks of 4 bytes in memory, shared with the test
+    #[derive(Clone)]
+    struct MemDevice(Arc<Mutex<[u8; 32]>>);
+
+    impl BlockDevice for MemDevice {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            let begin = block_id << 2;
+            buf[..4].copy_from_slice(&self.0.lock().unwrap()[begin..begin + 4]);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            let begin = block_id << 2;
+            self.0.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A cache used as the device of another one
+    struct Nested(Arc<BlockCache<MemDevice>>);
+
+    impl BlockDevice for Nested {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            BlockDevice::read_at(&*self.0, block_id, buf)
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            BlockDevice::write_at(&*self.0, block_id, buf)
+        }
+        fn sync(&self) -> Result<()> {
+            BlockDevice::sync(&*self.0)
+        }
+    }
+
+    #[test]
+    fn nested_budget() {
+        let budget = Arc::new(MemoryBudget::new(16));
+        let dev = MemDevice(Arc::new(Mutex::new([0; 32])));
+        let lower = BlockCache::with_budget(dev.clone(), 4, budget.clone());
+        let upper = BlockCache::with_budget(Nested(lower.clone()), 4, budget.clone());
+
+        // the upper cache takes the whole budget with dirty blocks
+        for i in 0..4u8 {
+            BlockDevice::write_at(&*upper, i as usize, &[i + 1; 4]).unwrap();
+        }
+        assert_eq!(budget.used(), 16);
+
+        // the lower cache makes it write back into the lower cache itself
+        BlockDevice::write_at(&*lower, 7, &[8; 4]).unwrap();
+        assert!(budget.used() <= 16);
+
+        drop(upper);
+        drop(lower);
+        assert_eq!(budget.used(), 0);
+        let data = dev.0.lock().unwrap();
+        assert_eq!(data[..16], [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4]);
+        assert_eq!(data[28..], [8; 4]);
+    }
+
+    #[test]
+    fn shared_budget() {
+        let budget = Arc::new(MemoryBudget::new(16));
+        let dev1 = MemDevice(Arc::new(Mutex::new([0; 32])));
+        let dev2 = MemDevice(Arc::new(Mutex::new([0; 32])));
+        let cache1 = BlockCache::with_budget(dev1.clone(), 4, budget.clone());
+        let cache2 = BlockCache::with_budget(dev2.clone(), 4, budget.clone());
+
+        // cache1 takes the whole budget with dirty blocks
+        for i in 0..4u8 {
+            BlockDevice::write_at(&*cache1, i as usize, &[i + 1; 4]).unwrap();
+        }
+        assert_eq!(budget.used(), 16);
+        assert_eq!(*dev1.0.lock().unwrap(), [0; 32]);
+
+        // cache2 evicts them, dirty data goes to the device
+        for i in 0..2u8 {
+            BlockDevice::write_at(&*cache2, i as usize, &[i + 10; 4]).unwrap();
+        }
+        assert!(budget.used() <= 16);
+        assert_eq!(dev1.0.lock().unwrap()[..8], [1, 1, 1, 1, 2, 2, 2, 2]);
+
+        // evicted blocks are read again from the device
+        let mut buf = [0u8; 4];
+        for i in 0..4u8 {
+            BlockDevice::read_at(&*cache1, i as usize, &mut buf).unwrap();
+            assert_eq!(buf, [i + 1; 4]);
+        }
+        for i in 0..2u8 {
+            BlockDevice::read_at(&*cache2, i as usize, &mut buf).unwrap();
+            assert_eq!(buf, [i + 10; 4]);
+        }
+        assert!(budget.used() <= 16);
+
+        drop(cache1);
+        drop(cache2);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(
+            dev2.0.lock().unwrap()[..8],
+            [10, 10, 10, 10, 11, 11, 11, 11]
+        );
+    }
+}
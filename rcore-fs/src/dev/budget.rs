@@ -0,0 +1,130 @@
+//! Memory budget shared by all caches of a file system
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// A cache which can give back memory to the budget
+pub trait Reclaim: Send + Sync {
+    /// Try to free at least `bytes` bytes, return the number of bytes freed.
+    ///
+    /// It may be called while the cache itself is charging the budget,
+    /// so implementations should skip the entries they can not lock.
+    fn reclaim(&self, bytes: usize) -> usize;
+}
+
+/// Global memory limit for caches
+///
+/// Every cache charges the bytes it allocates and uncharges them when freed.
+/// When the total exceeds `limit`, the registered caches are asked in turn
+/// to reclaim the excess.
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    caches: Mutex<Vec<Weak<dyn Reclaim>>>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            caches: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The limit in bytes
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes charged by all caches
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Register a cache to be asked for memory when over budget
+    pub fn register(&self, cache: Weak<dyn Reclaim>) {
+        self.caches.lock().push(cache);
+    }
+
+    /// Charge `bytes` to the budget, and evict from caches if over limit.
+    ///
+    /// Return false and charge nothing if the usage is still over limit after eviction.
+    pub fn charge(&self, bytes: usize) -> bool {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if used <= self.limit {
+            return true;
+        }
+        let mut excess = used - self.limit;
+        // reclaiming may charge again, e.g. a cache writing back to another one
+        let caches: Vec<Arc<dyn Reclaim>> = {
+            let mut caches = self.caches.lock();
+            caches.retain(|cache| cache.upgrade().is_some());
+            caches.iter().filter_map(|cache| cache.upgrade()).collect()
+        };
+        for cache in caches {
+            if excess == 0 {
+                break;
+            }
+            let freed = cache.reclaim(excess);
+            excess = excess.saturating_sub(freed);
+        }
+        if self.used() > self.limit {
+            self.uncharge(bytes);
+            return false;
+        }
+        true
+    }
+
+    /// Give back `bytes` to the budget
+    pub fn uncharge(&self, bytes: usize) {
+        let old = self.used.fetch_sub(bytes, Ordering::Relaxed);
+        debug_assert!(old >= bytes, "uncharge more than charged");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    /// A fake cache holding `held` bytes
+    struct Cache {
+        budget: Arc<MemoryBudget>,
+        held: AtomicUsize,
+    }
+
+    impl Reclaim for Cache {
+        fn reclaim(&self, bytes: usize) -> usize {
+            let freed = bytes.min(self.held.load(Ordering::Relaxed));
+            self.held.fetch_sub(freed, Ordering::Relaxed);
+            self.budget.uncharge(freed);
+            freed
+        }
+    }
+
+    #[test]
+    fn evict_when_over_limit() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let cache = Arc::new(Cache {
+            budget: budget.clone(),
+            held: AtomicUsize::new(80),
+        });
+        assert!(budget.charge(80));
+        let weak: Weak<dyn Reclaim> = Arc::downgrade(&(cache.clone() as Arc<dyn Reclaim>));
+        budget.register(weak);
+
+        // another cache takes 50 bytes, the first one must give back 30
+        assert!(budget.charge(50));
+        assert_eq!(budget.used(), 100);
+        assert_eq!(cache.held.load(Ordering::Relaxed), 50);
+
+        // nothing left to evict, the charge is given back
+        assert!(!budget.charge(60));
+        assert_eq!(budget.used(), 50);
+        assert_eq!(cache.held.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,19 +1,129 @@
 use crate::util::*;
 use crate::vfs::Timespec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
 
 pub mod block_cache;
+pub mod budget;
 pub mod std_impl;
+pub mod write_combine;
 
 /// A current time provider
 pub trait TimeProvider: Send + Sync {
     fn current_time(&self) -> Timespec;
 }
 
+/// A random number provider, e.g. for UUIDs and keys
+pub trait RngProvider: Send + Sync {
+    /// Fill `buf` with random bytes, fail if no randomness is available
+    fn fill_bytes(&self, buf: &mut [u8]) -> Result<()>;
+}
+
+/// Time provider always returning the same time, for reproducible results
+pub struct FixedTimeProvider(pub Timespec);
+
+impl TimeProvider for FixedTimeProvider {
+    fn current_time(&self) -> Timespec {
+        self.0
+    }
+}
+
+/// Pseudo random numbers generated from a seed, for reproducible results.
+///
+/// It is NOT cryptographically secure.
+pub struct SeededRngProvider {
+    state: Mutex<u64>,
+}
+
+impl SeededRngProvider {
+    pub const fn new(seed: u64) -> Self {
+        SeededRngProvider {
+            state: Mutex::new(seed),
+        }
+    }
+}
+
+impl RngProvider for SeededRngProvider {
+    fn fill_bytes(&self, buf: &mut [u8]) -> Result<()> {
+        let mut state = self.state.lock();
+        for chunk in buf.chunks_mut(8) {
+            // SplitMix64
+            *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = *state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+/// A token to abort an in-flight device operation
+pub trait CancelToken: Send + Sync {
+    /// Return true if the operation should be given up
+    fn is_cancelled(&self) -> bool;
+}
+
+/// Cancelled by calling `store(true)` from another thread
+impl CancelToken for AtomicBool {
+    fn is_cancelled(&self) -> bool {
+        self.load(Ordering::Relaxed)
+    }
+}
+
+/// Cancelled when the current time passes `time`
+pub struct Deadline {
+    pub time: Timespec,
+    pub time_provider: &'static dyn TimeProvider,
+}
+
+impl CancelToken for Deadline {
+    fn is_cancelled(&self) -> bool {
+        self.time_provider.current_time() >= self.time
+    }
+}
+
+/// Return `Err(DevError::TimedOut)` if `token` is cancelled
+pub fn check_cancel(token: Option<&dyn CancelToken>) -> Result<()> {
+    match token {
+        Some(token) if token.is_cancelled() => Err(DevError::TimedOut),
+        _ => Ok(()),
+    }
+}
+
 /// Interface for FS to read & write
 pub trait Device: Send + Sync {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize>;
     fn sync(&self) -> Result<()>;
+
+    /// Same as `read_at`, but give up when `token` is cancelled
+    fn read_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        check_cancel(token)?;
+        self.read_at(offset, buf)
+    }
+
+    /// Same as `write_at`, but give up when `token` is cancelled
+    fn write_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        check_cancel(token)?;
+        self.write_at(offset, buf)
+    }
+
+    /// Whether writes always fail
+    fn is_read_only(&self) -> bool {
+        false
+    }
 }
 
 /// Device which can only R/W in blocks
@@ -26,7 +136,12 @@ pub trait BlockDevice: Send + Sync {
 
 /// The error type for device.
 #[derive(Debug, PartialEq, Eq)]
-pub struct DevError;
+pub enum DevError {
+    /// The device failed to complete the operation
+    IOError,
+    /// The operation was cancelled or its deadline has passed
+    TimedOut,
+}
 
 /// A specialized `Result` type for device.
 pub type Result<T> = core::result::Result<T, DevError>;
@@ -44,6 +159,24 @@ macro_rules! try0 {
 /// Helper functions to R/W BlockDevice in bytes
 impl<T: BlockDevice> Device for T {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.read_at_cancellable(offset, buf, None)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.write_at_cancellable(offset, buf, None)
+    }
+
+    fn sync(&self) -> Result<()> {
+        BlockDevice::sync(self)
+    }
+
+    /// Check `token` before each block
+    fn read_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
         let iter = BlockIter {
             begin: offset,
             end: offset + buf.len(),
@@ -52,6 +185,7 @@ impl<T: BlockDevice> Device for T {
 
         // For each block
         for range in iter {
+            check_cancel(token)?;
             let len = range.origin_begin() - offset;
             let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
             if range.is_full() {
@@ -70,7 +204,13 @@ impl<T: BlockDevice> Device for T {
         Ok(buf.len())
     }
 
-    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+    /// Check `token` before each block
+    fn write_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
         let iter = BlockIter {
             begin: offset,
             end: offset + buf.len(),
@@ -79,6 +219,7 @@ impl<T: BlockDevice> Device for T {
 
         // For each block
         for range in iter {
+            check_cancel(token)?;
             let len = range.origin_begin() - offset;
             let buf = &buf[range.origin_begin() - offset..range.origin_end() - offset];
             if range.is_full() {
@@ -98,9 +239,30 @@ impl<T: BlockDevice> Device for T {
         }
         Ok(buf.len())
     }
+}
+
+/// Read-only device on an image in memory, e.g. linked into the kernel.
+///
+/// Writes fail with `IOError`, SFS on it rejects changes with `ReadOnlyFs`
+/// before trying them.
+impl Device for &'static [u8] {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let begin = offset.min(self.len());
+        let len = buf.len().min(self.len() - begin);
+        buf[..len].copy_from_slice(&self[begin..begin + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(DevError::IOError)
+    }
 
     fn sync(&self) -> Result<()> {
-        BlockDevice::sync(self)
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
     }
 }
 
@@ -113,7 +275,7 @@ mod test {
         const BLOCK_SIZE_LOG2: u8 = 2;
         fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevError::IOError);
             }
             let begin = block_id << 2;
             buf[..4].copy_from_slice(&mut self.lock().unwrap()[begin..begin + 4]);
@@ -121,7 +283,7 @@ mod test {
         }
         fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevError::IOError);
             }
             let begin = block_id << 2;
             self.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
@@ -183,4 +345,32 @@ mod test {
             [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 3, 4, 5, 6, 7]
         );
     }
+
+    #[test]
+    fn cancel() {
+        let buf: Mutex<[u8; 16]> = Mutex::new([0; 16]);
+        let mut res: [u8; 6] = [0; 6];
+        let token = AtomicBool::new(false);
+
+        let ret = Device::read_at_cancellable(&buf, 3, &mut res, Some(&token));
+        assert_eq!(ret, Ok(6));
+
+        token.store(true, Ordering::Relaxed);
+        let ret = Device::read_at_cancellable(&buf, 3, &mut res, Some(&token));
+        assert_eq!(ret, Err(DevError::TimedOut));
+        let ret = Device::write_at_cancellable(&buf, 3, &res, Some(&token));
+        assert_eq!(ret, Err(DevError::TimedOut));
+        assert_eq!(*buf.lock().unwrap(), [0; 16]);
+    }
+
+    #[test]
+    fn static_image() {
+        static IMAGE: [u8; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
+        let dev: &'static [u8] = &IMAGE;
+        let mut res: [u8; 6] = [0; 6];
+        assert_eq!(dev.read_at(4, &mut res), Ok(4));
+        assert_eq!(res, [4, 5, 6, 7, 0, 0]);
+        assert_eq!(dev.read_at(9, &mut res), Ok(0));
+        assert_eq!(dev.write_at(0, &res), Err(DevError::IOError));
+    }
 }
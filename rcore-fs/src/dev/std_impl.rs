@@ -1,7 +1,7 @@
 #![cfg(any(test, feature = "std"))]
 
 use std::fs::File;
-use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -9,26 +9,50 @@ use super::*;
 
 impl Device for Mutex<File> {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.read_at_cancellable(offset, buf, None)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.write_at_cancellable(offset, buf, None)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let file = self.lock().unwrap();
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn read_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        check_cancel(token)?;
         let offset = offset as u64;
         let mut file = self.lock().unwrap();
+        // waiting for the lock may take a while
+        check_cancel(token)?;
         file.seek(SeekFrom::Start(offset))?;
         let len = file.read(buf)?;
         Ok(len)
     }
 
-    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+    fn write_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        check_cancel(token)?;
         let offset = offset as u64;
         let mut file = self.lock().unwrap();
+        // waiting for the lock may take a while
+        check_cancel(token)?;
         file.seek(SeekFrom::Start(offset))?;
         let len = file.write(buf)?;
         Ok(len)
     }
-
-    fn sync(&self) -> Result<()> {
-        let file = self.lock().unwrap();
-        file.sync_all()?;
-        Ok(())
-    }
 }
 
 pub struct StdTimeProvider;
@@ -43,8 +67,23 @@ impl TimeProvider for StdTimeProvider {
     }
 }
 
+/// Random numbers from the OS
+#[cfg(unix)]
+pub struct StdRngProvider;
+
+#[cfg(unix)]
+impl RngProvider for StdRngProvider {
+    fn fill_bytes(&self, buf: &mut [u8]) -> Result<()> {
+        File::open("/dev/urandom")?.read_exact(buf)?;
+        Ok(())
+    }
+}
+
 impl From<Error> for DevError {
-    fn from(_: Error) -> Self {
-        DevError
+    fn from(e: Error) -> Self {
+        match e.kind() {
+            ErrorKind::TimedOut => DevError::TimedOut,
+            _ => DevError::IOError,
+        }
     }
 }
@@ -0,0 +1,374 @@
+//! A write-combining layer for `Device`
+//!
+//! Writes are queued and merged with adjacent or overlapping ones, and reach
+//! the device on `sync`, or before a write would make the queued bytes exceed
+//! the window. A write failing to flush the queue is not queued. Errors writing
+//! earlier writes are returned by the next flush or `sync`.
+//!
+//! With a `MemoryBudget`, queued bytes are charged to it and flushed when other
+//! caches need memory. A write which can not be charged goes to the device.
+use super::budget::{MemoryBudget, Reclaim};
+use super::*;
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+
+/// Queue writes to `device` and merge them before they reach it
+pub struct WriteCombine<T: Device> {
+    device: T,
+    /// Max bytes queued before flushing
+    window: usize,
+    queue: Mutex<Queue>,
+    /// If set, queued bytes are charged to it
+    budget: Option<Arc<MemoryBudget>>,
+}
+
+struct Queue {
+    /// Disjoint and non-adjacent extents: offset -> data
+    extents: BTreeMap<usize, Vec<u8>>,
+    /// Total bytes in `extents`
+    bytes: usize,
+    stats: WriteCombineStats,
+}
+
+/// Statistics of a `WriteCombine`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriteCombineStats {
+    /// Number of writes received
+    pub writes: usize,
+    /// Number of times a write was merged with a queued one
+    pub merges: usize,
+    /// Number of writes issued to the device
+    pub device_writes: usize,
+}
+
+impl<T: Device> WriteCombine<T> {
+    pub fn new(device: T, window: usize) -> Self {
+        WriteCombine {
+            device,
+            window,
+            queue: Mutex::new(Queue {
+                extents: BTreeMap::new(),
+                bytes: 0,
+                stats: WriteCombineStats::default(),
+            }),
+            budget: None,
+        }
+    }
+
+    /// Create a layer whose queued bytes are charged to `budget`
+    pub fn with_budget(device: T, window: usize, budget: Arc<MemoryBudget>) -> Arc<Self>
+    where
+        T: 'static,
+    {
+        let mut combine = Self::new(device, window);
+        combine.budget = Some(budget.clone());
+        let combine = Arc::new(combine);
+        let reclaim: Arc<dyn Reclaim> = combine.clone();
+        budget.register(Arc::downgrade(&reclaim));
+        combine
+    }
+
+    pub fn stats(&self) -> WriteCombineStats {
+        self.queue.lock().stats
+    }
+
+    /// Write all queued extents to the device
+    pub fn flush(&self) -> Result<()> {
+        self.flush_queue(&mut self.queue.lock())
+    }
+
+    /// Flush `queue` and give back the bytes written to the budget
+    fn flush_queue(&self, queue: &mut Queue) -> Result<()> {
+        let bytes = queue.bytes;
+        let result = queue.flush(&self.device);
+        self.uncharge(bytes - queue.bytes);
+        result
+    }
+
+    fn uncharge(&self, bytes: usize) {
+        if let Some(budget) = &self.budget {
+            budget.uncharge(bytes);
+        }
+    }
+}
+
+impl Queue {
+    fn push(&mut self, offset: usize, buf: &[u8]) {
+        let end = offset + buf.len();
+        self.stats.writes += 1;
+        // extents to merge are adjacent to each other in the map
+        let merged: Vec<usize> = self
+            .extents
+            .range(..=end)
+            .rev()
+            .take_while(|(&begin, data)| begin + data.len() >= offset)
+            .map(|(&begin, _)| begin)
+            .collect();
+        self.stats.merges += merged.len();
+        let begin = merged.last().map_or(offset, |&begin| begin.min(offset));
+        let end = merged
+            .iter()
+            .map(|begin| begin + self.extents[begin].len())
+            .fold(end, usize::max);
+        let mut data = vec![0u8; end - begin];
+        for old in merged {
+            let old_data = self.extents.remove(&old).unwrap();
+            self.bytes -= old_data.len();
+            data[old - begin..old - begin + old_data.len()].copy_from_slice(&old_data);
+        }
+        data[offset - begin..offset - begin + buf.len()].copy_from_slice(buf);
+        self.bytes += data.len();
+        self.extents.insert(begin, data);
+    }
+
+    fn flush(&mut self, device: &dyn Device) -> Result<()> {
+        while let Some(&offset) = self.extents.keys().next() {
+            let data = self.extents.remove(&offset).unwrap();
+            let result = match device.write_at(offset, &data) {
+                Ok(len) if len == data.len() => Ok(()),
+                Ok(_) => Err(DevError::IOError),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                // keep it for the next try
+                self.extents.insert(offset, data);
+                return Err(e);
+            }
+            self.bytes -= data.len();
+            self.stats.device_writes += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Device> Device for WriteCombine<T> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let end = offset + buf.len();
+        // copy the queued data in range, so the device is read without the lock
+        let queued: Vec<(usize, Vec<u8>)> = self
+            .queue
+            .lock()
+            .extents
+            .range(..end)
+            .filter_map(|(&begin, data)| {
+                let (from, to) = (begin.max(offset), (begin + data.len()).min(end));
+                if from >= to {
+                    return None;
+                }
+                Some((from - offset, data[from - begin..to - begin].to_vec()))
+            })
+            .collect();
+        let mut len = self.device.read_at(offset, buf)?;
+        // overlay queued data, zero filling the gap after a short read
+        for (from, data) in queued {
+            if from > len {
+                buf[len..from].iter_mut().for_each(|b| *b = 0);
+            }
+            buf[from..from + data.len()].copy_from_slice(&data);
+            len = len.max(from + data.len());
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        // charge before taking the lock, as other caches may reclaim into this one
+        let charged = match &self.budget {
+            Some(budget) => budget.charge(buf.len()),
+            None => true,
+        };
+        let mut queue = self.queue.lock();
+        if !charged {
+            // no memory to queue it
+            self.flush_queue(&mut queue)?;
+            return self.device.write_at(offset, buf);
+        }
+        if queue.bytes + buf.len() > self.window {
+            if let Err(e) = self.flush_queue(&mut queue) {
+                self.uncharge(buf.len());
+                return Err(e);
+            }
+        }
+        let bytes = queue.bytes;
+        queue.push(offset, buf);
+        // merged bytes were charged already
+        self.uncharge(bytes + buf.len() - queue.bytes);
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.flush()?;
+        self.device.sync()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
+impl<T: Device> Drop for WriteCombine<T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("failed to flush queued writes: {:?}", e);
+        }
+        let bytes = self.queue.lock().bytes;
+        self.uncharge(bytes);
+    }
+}
+
+impl<T: Device> Reclaim for WriteCombine<T> {
+    /// Flush the queue unless it is in use
+    fn reclaim(&self, _bytes: usize) -> usize {
+        match self.queue.try_lock() {
+            Some(mut queue) => {
+                let bytes = queue.bytes;
+                self.flush_queue(&mut queue).ok();
+                bytes - queue.bytes
+            }
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A device counting the writes
+    struct Mem {
+        data: Mutex<Vec<u8>>,
+        writes: Mutex<usize>,
+        /// Fail writes with `TimedOut`
+        timed_out: Mutex<bool>,
+    }
+
+    impl Device for Mem {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.data.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            if *self.timed_out.lock().unwrap() {
+                return Err(DevError::TimedOut);
+            }
+            let mut data = self.data.lock().unwrap();
+            if data.len() < offset + buf.len() {
+                data.resize(offset + buf.len(), 0);
+            }
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            *self.writes.lock().unwrap() += 1;
+            Ok(buf.len())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn combine() {
+        let dev = WriteCombine::new(
+            Mem {
+                data: Mutex::new(vec![0; 32]),
+                writes: Mutex::new(0),
+                timed_out: Mutex::new(false),
+            },
+            16,
+        );
+        dev.write_at(4, b"bb").unwrap();
+        dev.write_at(0, b"aaaa").unwrap();
+        dev.write_at(5, b"cc").unwrap();
+        dev.write_at(10, b"d").unwrap();
+        let mut buf = [0u8; 12];
+        assert_eq!(dev.read_at(0, &mut buf), Ok(12));
+        assert_eq!(&buf, b"aaaabcc\0\0\0d\0");
+        assert_eq!(*dev.device.writes.lock().unwrap(), 0);
+
+        dev.sync().unwrap();
+        assert_eq!(*dev.device.writes.lock().unwrap(), 2);
+        assert_eq!(&dev.device.data.lock().unwrap()[..12], b"aaaabcc\0\0\0d\0");
+        assert_eq!(
+            dev.stats(),
+            WriteCombineStats {
+                writes: 4,
+                merges: 2,
+                device_writes: 2,
+            }
+        );
+
+        // flush before exceeding the window
+        dev.write_at(0, &[1; 10]).unwrap();
+        dev.write_at(20, &[2; 10]).unwrap();
+        assert_eq!(dev.stats().device_writes, 3);
+
+        // errors of the device are kept, and so is the data
+        *dev.device.timed_out.lock().unwrap() = true;
+        dev.write_at(0, b"e").unwrap();
+        assert_eq!(dev.sync(), Err(DevError::TimedOut));
+        *dev.device.timed_out.lock().unwrap() = false;
+        dev.sync().unwrap();
+        assert_eq!(dev.device.data.lock().unwrap()[0], b'e');
+    }
+
+    #[test]
+    fn failed_flush() {
+        let dev = WriteCombine::new(
+            Mem {
+                data: Mutex::new(vec![0; 8]),
+                writes: Mutex::new(0),
+                timed_out: Mutex::new(false),
+            },
+            16,
+        );
+        // the gap between a short read and queued data is zeroed
+        dev.write_at(12, b"ab").unwrap();
+        let mut buf = [0xff; 16];
+        assert_eq!(dev.read_at(4, &mut buf), Ok(10));
+        assert_eq!(&buf[..10], b"\0\0\0\0\0\0\0\0ab");
+
+        // a write failing to flush the queue is not queued
+        *dev.device.timed_out.lock().unwrap() = true;
+        assert_eq!(dev.write_at(0, &[1; 15]), Err(DevError::TimedOut));
+        *dev.device.timed_out.lock().unwrap() = false;
+        dev.sync().unwrap();
+        assert_eq!(
+            &dev.device.data.lock().unwrap()[..],
+            b"\0\0\0\0\0\0\0\0\0\0\0\0ab"
+        );
+    }
+
+    #[test]
+    fn shared_budget() {
+        let budget = Arc::new(MemoryBudget::new(16));
+        let mem = || Mem {
+            data: Mutex::new(vec![0; 32]),
+            writes: Mutex::new(0),
+            timed_out: Mutex::new(false),
+        };
+        let dev1 = WriteCombine::with_budget(mem(), 16, budget.clone());
+        let dev2 = WriteCombine::with_budget(mem(), 16, budget.clone());
+        dev1.write_at(0, &[1; 8]).unwrap();
+        dev1.write_at(4, &[2; 8]).unwrap();
+        assert_eq!(budget.used(), 12);
+
+        // dev1 is flushed to make room for dev2
+        dev2.write_at(0, &[3; 8]).unwrap();
+        assert_eq!(budget.used(), 8);
+        assert_eq!(dev1.stats().device_writes, 1);
+        assert_eq!(
+            dev1.device.data.lock().unwrap()[..12],
+            [1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2]
+        );
+
+        // over the budget even after reclaiming, written through
+        dev1.write_at(0, &[4; 20]).unwrap();
+        assert_eq!(budget.used(), 0);
+        assert_eq!(dev1.device.data.lock().unwrap()[..20], [4; 20]);
+        assert_eq!(dev2.device.data.lock().unwrap()[..8], [3; 8]);
+
+        drop(dev1);
+        drop(dev2);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -1,32 +1,128 @@
-use crate::vfs::{INode, Metadata, Result};
+use crate::vfs::{FileType, FsError, INode, Metadata, Result};
 use alloc::{string::String, sync::Arc};
+use bitflags::bitflags;
+
+bitflags! {
+    /// Flags for opening a file, with the same values as Linux
+    pub struct OpenFlags: u32 {
+        /// read only
+        const RDONLY = 0;
+        /// write only
+        const WRONLY = 0o1;
+        /// read and write
+        const RDWR = 0o2;
+        /// create the file if not exists
+        const CREATE = 0o100;
+        /// with `CREATE`, fail if the file exists
+        const EXCL = 0o200;
+        /// truncate the file to 0 when opened for writing
+        const TRUNC = 0o1000;
+        /// write at the end of the file
+        const APPEND = 0o2000;
+        /// fail if not a directory
+        const DIRECTORY = 0o200000;
+        /// do not follow the symlink at the end of the path
+        const NOFOLLOW = 0o400000;
+    }
+}
+
+impl OpenFlags {
+    /// Mask of the access mode
+    const ACCMODE: u32 = 0o3;
+
+    /// Whether the access mode is one of `RDONLY`, `WRONLY` and `RDWR`
+    pub fn valid_access_mode(&self) -> bool {
+        self.bits() & Self::ACCMODE != Self::ACCMODE
+    }
+
+    pub fn readable(&self) -> bool {
+        let mode = self.bits() & Self::ACCMODE;
+        mode == Self::RDONLY.bits() || mode == Self::RDWR.bits()
+    }
+
+    pub fn writable(&self) -> bool {
+        let mode = self.bits() & Self::ACCMODE;
+        mode == Self::WRONLY.bits() || mode == Self::RDWR.bits()
+    }
+}
 
 pub struct File {
     inode: Arc<dyn INode>,
     offset: usize,
+    flags: OpenFlags,
+    /// Checked instead of the access mode in `flags`,
+    /// which can not tell that `File::new` gave no access at all
     readable: bool,
     writable: bool,
 }
 
 impl File {
     pub fn new(inode: Arc<dyn INode>, readable: bool, writable: bool) -> Self {
+        let flags = match (readable, writable) {
+            (true, true) => OpenFlags::RDWR,
+            (false, true) => OpenFlags::WRONLY,
+            _ => OpenFlags::RDONLY,
+        };
         File {
             inode,
             offset: 0,
+            flags,
             readable,
             writable,
         }
     }
 
+    /// Open `inode` with `flags`.
+    ///
+    /// Path related flags (`CREATE`, `EXCL`) are handled by `INode::open`.
+    pub fn open(inode: Arc<dyn INode>, flags: OpenFlags) -> Result<Self> {
+        if !flags.valid_access_mode() {
+            return Err(FsError::InvalidParam);
+        }
+        let type_ = inode.metadata()?.type_;
+        if type_ == FileType::SymLink && flags.contains(OpenFlags::NOFOLLOW) {
+            return Err(FsError::SymLoop);
+        }
+        if type_ != FileType::Dir && flags.contains(OpenFlags::DIRECTORY) {
+            return Err(FsError::NotDir);
+        }
+        if type_ == FileType::Dir && flags.writable() {
+            return Err(FsError::IsDir);
+        }
+        if type_ == FileType::File && flags.writable() && flags.contains(OpenFlags::TRUNC) {
+            inode.resize(0)?;
+        }
+        Ok(File {
+            inode,
+            offset: 0,
+            flags,
+            readable: flags.readable(),
+            writable: flags.writable(),
+        })
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        assert!(self.readable);
+        if !self.readable {
+            return Err(FsError::BadFd);
+        }
         let len = self.inode.read_at(self.offset, buf)?;
         self.offset += len;
         Ok(len)
     }
 
     pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        assert!(self.writable);
+        if !self.writable {
+            return Err(FsError::BadFd);
+        }
+        if self.flags.contains(OpenFlags::APPEND) {
+            let (offset, len) = self.inode.append(buf)?;
+            self.offset = offset + len;
+            return Ok(len);
+        }
         let len = self.inode.write_at(self.offset, buf)?;
         self.offset += len;
         Ok(len)
@@ -40,3 +136,18 @@ impl File {
         self.inode.get_entry(id)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn access_mode() {
+        assert!(OpenFlags::RDONLY.readable() && !OpenFlags::RDONLY.writable());
+        assert!(!OpenFlags::WRONLY.readable() && OpenFlags::WRONLY.writable());
+        assert!(OpenFlags::RDWR.readable() && OpenFlags::RDWR.writable());
+        let flags = OpenFlags::WRONLY | OpenFlags::APPEND | OpenFlags::CREATE;
+        assert!(!flags.readable() && flags.writable());
+        assert!(!(OpenFlags::WRONLY | OpenFlags::RDWR).valid_access_mode());
+    }
+}
@@ -2,10 +2,13 @@
 #![feature(async_closure)]
 
 extern crate alloc;
+#[macro_use]
+extern crate log;
 
 pub mod dev;
 pub mod dirty;
 pub mod file;
+pub mod maintenance;
 pub mod util;
 pub mod vfs;
 
@@ -0,0 +1,166 @@
+//! Background maintenance work of file systems
+//!
+//! Subsystems like flushers or garbage collectors implement `MaintenanceTask`.
+//! A file system runs them from `FileSystem::run_maintenance`, which no_std
+//! kernels call periodically. With `std` feature, `MaintenanceThread` does it
+//! in a background thread.
+use crate::vfs::Result;
+
+/// A piece of background work, performed in bounded steps
+pub trait MaintenanceTask: Send + Sync {
+    /// Perform at most `budget` units of work, return the units performed.
+    ///
+    /// The meaning of a unit is up to the task, e.g. one inode flushed.
+    /// Return 0 if there is nothing to do.
+    fn run(&self, budget: usize) -> Result<usize>;
+}
+
+/// Run `tasks` in order until `budget` is used up, return the units performed
+pub fn run_tasks(tasks: &[&dyn MaintenanceTask], budget: usize) -> Result<usize> {
+    let mut done = 0;
+    for task in tasks {
+        if done >= budget {
+            break;
+        }
+        done += task.run(budget - done)?;
+    }
+    Ok(done)
+}
+
+#[cfg(any(test, feature = "std"))]
+pub use self::thread::MaintenanceThread;
+
+#[cfg(any(test, feature = "std"))]
+mod thread {
+    use crate::vfs::FileSystem;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    /// A thread calling `run_maintenance` of a file system periodically.
+    ///
+    /// The thread is stopped when dropped.
+    pub struct MaintenanceThread {
+        stop: Arc<AtomicBool>,
+        handle: Option<JoinHandle<()>>,
+    }
+
+    impl MaintenanceThread {
+        /// Run `budget` units of maintenance on `fs` every `interval`
+        pub fn spawn(fs: Arc<dyn FileSystem>, budget: usize, interval: Duration) -> Self {
+            let stop = Arc::new(AtomicBool::new(false));
+            let stop1 = stop.clone();
+            let handle = thread::spawn(move || {
+                while !stop1.load(Ordering::Relaxed) {
+                    if let Err(e) = fs.run_maintenance(budget) {
+                        warn!("maintenance failed: {:?}", e);
+                    }
+                    thread::park_timeout(interval);
+                }
+            });
+            MaintenanceThread {
+                stop,
+                handle: Some(handle),
+            }
+        }
+    }
+
+    impl Drop for MaintenanceThread {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+            let handle = self.handle.take().unwrap();
+            handle.thread().unpark();
+            handle.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vfs::*;
+    use alloc::sync::Arc;
+    use core::any::Any;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// A task with `left` units of work to do
+    struct Task {
+        left: AtomicUsize,
+    }
+
+    impl MaintenanceTask for Task {
+        fn run(&self, budget: usize) -> Result<usize> {
+            let done = budget.min(self.left.load(Ordering::Relaxed));
+            self.left.fetch_sub(done, Ordering::Relaxed);
+            Ok(done)
+        }
+    }
+
+    /// An empty root, the tests only run maintenance
+    impl INode for Task {
+        fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+            Err(FsError::NotSupported)
+        }
+        fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+            Err(FsError::NotSupported)
+        }
+        fn poll(&self) -> Result<PollStatus> {
+            Err(FsError::NotSupported)
+        }
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    impl FileSystem for Task {
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+        fn root_inode(&self) -> Arc<dyn INode> {
+            Arc::new(task(0))
+        }
+        fn info(&self) -> FsInfo {
+            FsInfo {
+                bsize: 0,
+                frsize: 0,
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files: 0,
+                ffree: 0,
+                namemax: 0,
+            }
+        }
+        fn run_maintenance(&self, budget: usize) -> Result<usize> {
+            self.run(budget)
+        }
+    }
+
+    fn task(left: usize) -> Task {
+        Task {
+            left: AtomicUsize::new(left),
+        }
+    }
+
+    #[test]
+    fn run_tasks_in_budget() {
+        let (a, b) = (task(3), task(5));
+        assert_eq!(run_tasks(&[&a, &b], 4), Ok(4));
+        assert_eq!(a.left.load(Ordering::Relaxed), 0);
+        assert_eq!(b.left.load(Ordering::Relaxed), 4);
+        assert_eq!(run_tasks(&[&a, &b], 10), Ok(4));
+        assert_eq!(run_tasks(&[&a, &b], 10), Ok(0));
+    }
+
+    #[test]
+    fn thread() {
+        let fs = Arc::new(task(100));
+        let runner = MaintenanceThread::spawn(fs.clone(), 10, Duration::from_millis(1));
+        while fs.left.load(Ordering::Relaxed) != 0 {
+            std::thread::yield_now();
+        }
+        drop(runner);
+    }
+}
@@ -16,6 +16,16 @@ impl std::error::Error for FsError {}
 impl From<std::io::Error> for FsError {
     fn from(e: Error) -> Self {
         use std::io::ErrorKind;
+        #[cfg(unix)]
+        match e.raw_os_error() {
+            Some(libc::ENOTEMPTY) => return FsError::DirNotEmpty,
+            Some(libc::ENOTDIR) => return FsError::NotDir,
+            Some(libc::EISDIR) => return FsError::IsDir,
+            Some(libc::EXDEV) => return FsError::NotSameFs,
+            Some(libc::ENOSPC) => return FsError::NoDeviceSpace,
+            Some(libc::EPERM) => return FsError::OperationNotPermitted,
+            _ => {}
+        }
         match e.kind() {
             ErrorKind::NotFound => FsError::EntryNotFound,
             // We do not have permission in our fs, just ignore the file
@@ -24,6 +34,7 @@ impl From<std::io::Error> for FsError {
             ErrorKind::WouldBlock => FsError::Again,
             ErrorKind::InvalidInput => FsError::InvalidParam,
             ErrorKind::InvalidData => FsError::InvalidParam,
+            ErrorKind::TimedOut => FsError::TimedOut,
             // The host fs is the device here
             _ => FsError::DeviceError,
         }
@@ -1,4 +1,5 @@
-use crate::dev::DevError;
+use crate::dev::{check_cancel, CancelToken, DevError};
+use crate::file::{File, OpenFlags};
 use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
 use core::any::Any;
 use core::fmt;
@@ -6,6 +7,8 @@ use core::future::Future;
 use core::pin::Pin;
 use core::result;
 use core::str;
+#[cfg(feature = "std")]
+use serde::{Deserialize, Serialize};
 
 /// Abstract file system object such as file or directory.
 pub trait INode: Any + Sync + Send {
@@ -15,6 +18,39 @@ pub trait INode: Any + Sync + Send {
     /// Write bytes at `offset` from `buf`, return the number of bytes written.
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize>;
 
+    /// Same as `read_at`, but give up with `TimedOut` when `token` is cancelled
+    fn read_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        check_cancel(token)?;
+        self.read_at(offset, buf)
+    }
+
+    /// Same as `write_at`, but give up with `TimedOut` when `token` is cancelled
+    fn write_at_cancellable(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        token: Option<&dyn CancelToken>,
+    ) -> Result<usize> {
+        check_cancel(token)?;
+        self.write_at(offset, buf)
+    }
+
+    /// Write `buf` at the end of the file, return the offset written at
+    /// and the number of bytes written.
+    ///
+    /// Concurrent appends must not overwrite each other. This default reads
+    /// the size and then writes, file systems with concurrent writers override it.
+    fn append(&self, buf: &[u8]) -> Result<(usize, usize)> {
+        let offset = self.metadata()?.size;
+        let len = self.write_at(offset, buf)?;
+        Ok((offset, len))
+    }
+
     /// Poll the events, return a bitmap of events.
     fn poll(&self) -> Result<PollStatus>;
 
@@ -93,6 +129,41 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Byte version of `find()`, for names which may not be UTF-8.
+    ///
+    /// Names may contain any bytes except NUL and '/'.
+    /// The byte versions default to the `&str` ones, supporting UTF-8 names only.
+    fn find_bytes(&self, name: &[u8]) -> Result<Arc<dyn INode>> {
+        // a non UTF-8 name can not exist
+        self.find(str::from_utf8(name).map_err(|_| FsError::EntryNotFound)?)
+    }
+
+    /// Byte version of `create()`, see `find_bytes()`
+    fn create_bytes(&self, name: &[u8], type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        self.create(utf8_name(name)?, type_, mode)
+    }
+
+    /// Byte version of `link()`, see `find_bytes()`
+    fn link_bytes(&self, name: &[u8], other: &Arc<dyn INode>) -> Result<()> {
+        self.link(utf8_name(name)?, other)
+    }
+
+    /// Byte version of `unlink()`, see `find_bytes()`
+    fn unlink_bytes(&self, name: &[u8]) -> Result<()> {
+        self.unlink(str::from_utf8(name).map_err(|_| FsError::EntryNotFound)?)
+    }
+
+    /// Byte version of `move_()`, see `find_bytes()`
+    fn move_bytes(&self, old_name: &[u8], target: &Arc<dyn INode>, new_name: &[u8]) -> Result<()> {
+        let old_name = str::from_utf8(old_name).map_err(|_| FsError::EntryNotFound)?;
+        self.move_(old_name, target, utf8_name(new_name)?)
+    }
+
+    /// Byte version of `get_entry()`, see `find_bytes()`
+    fn get_entry_bytes(&self, id: usize) -> Result<Vec<u8>> {
+        Ok(self.get_entry(id)?.into_bytes())
+    }
+
     /// Get the name of directory entry with metadata
     fn get_entry_with_metadata(&self, id: usize) -> Result<(Metadata, String)> {
         // a default and slow implementation
@@ -116,17 +187,52 @@ pub trait INode: Any + Sync + Send {
         unimplemented!();
     }
 
+    /// Get the FS-specific extension `id`, see the ids defined by each file system.
+    ///
+    /// Wrappers like MountFS forward it to the inner INode, unlike `as_any_ref`.
+    fn query_extension(&self, _id: ExtensionId) -> Option<&dyn Any> {
+        None
+    }
+
     /// This is used to implement dynamics cast.
     /// Simply return self in the implement of the function.
     fn as_any_ref(&self) -> &dyn Any;
 }
 
+/// Id of an FS-specific extension of INode
+///
+/// Ids are defined by file systems along with the type of the extension.
+/// Use the crate name as the prefix to avoid conflicts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExtensionId(pub &'static str);
+
+/// Check a name to be created: not empty, no NUL or '/'
+pub fn check_name(name: &[u8]) -> Result<()> {
+    if name.is_empty() || name.iter().any(|&b| b == 0 || b == b'/') {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(())
+}
+
+/// Convert a name to be created to `&str`, for file systems storing UTF-8 names only
+fn utf8_name(name: &[u8]) -> Result<&str> {
+    str::from_utf8(name).map_err(|_| FsError::InvalidParam)
+}
+
+/// Maximum number of symlinks followed by `INode::open`
+pub const MAX_SYMLINK_FOLLOW: usize = 40;
+
 impl dyn INode {
     /// Downcast the INode to specific struct
     pub fn downcast_ref<T: INode>(&self) -> Option<&T> {
         self.as_any_ref().downcast_ref::<T>()
     }
 
+    /// Get the extension `id` of type `T`
+    pub fn extension<T: Any>(&self, id: ExtensionId) -> Option<&T> {
+        self.query_extension(id)?.downcast_ref::<T>()
+    }
+
     /// Get all directory entries as a Vec
     pub fn list(&self) -> Result<Vec<String>> {
         let info = self.metadata()?;
@@ -140,6 +246,49 @@ impl dyn INode {
             .collect())
     }
 
+    /// Get all directory entries as a Vec of byte names
+    pub fn list_bytes(&self) -> Result<Vec<Vec<u8>>> {
+        let info = self.metadata()?;
+        if info.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        Ok((0..)
+            .map(|i| self.get_entry_bytes(i))
+            .take_while(|result| result.is_ok())
+            .filter_map(|result| result.ok())
+            .collect())
+    }
+
+    /// Open the file at `path` from current INode, see `OpenFlags` for details
+    pub fn open(&self, path: &str, flags: OpenFlags, mode: u32) -> Result<File> {
+        if !flags.valid_access_mode() {
+            return Err(FsError::InvalidParam);
+        }
+        let (dir_path, name) = match path.rfind('/') {
+            Some(pos) => (&path[..=pos], &path[pos + 1..]),
+            None => ("", path),
+        };
+        let dir = self.lookup_follow(dir_path, MAX_SYMLINK_FOLLOW)?;
+        let found = if name == "" {
+            Ok(dir.clone())
+        } else if flags.contains(OpenFlags::NOFOLLOW) {
+            dir.find(name)
+        } else {
+            dir.lookup_follow(name, MAX_SYMLINK_FOLLOW)
+        };
+        let inode = match found {
+            Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCL) => {
+                return Err(FsError::EntryExist)
+            }
+            Ok(inode) => inode,
+            Err(FsError::EntryNotFound) if flags.contains(OpenFlags::CREATE) => {
+                dir.create(name, FileType::File, mode)?
+            }
+            Err(e) => return Err(e),
+        };
+        File::open(inode, flags)
+    }
+
     /// Lookup path from current INode, and do not follow symlinks
     pub fn lookup(&self, path: &str) -> Result<Arc<dyn INode>> {
         self.lookup_follow(path, 0)
@@ -235,6 +384,7 @@ pub struct MMapArea {
 ///
 /// Ref: [http://pubs.opengroup.org/onlinepubs/009604499/basedefs/sys/stat.h.html]
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub struct Metadata {
     /// Device ID
     pub dev: usize, // (major << 8) | minor
@@ -275,12 +425,14 @@ pub struct Metadata {
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub struct Timespec {
     pub sec: i64,
     pub nsec: i32,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub enum FileType {
     File,
     Dir,
@@ -295,6 +447,7 @@ pub enum FileType {
 ///
 /// Ref: [http://pubs.opengroup.org/onlinepubs/9699919799/]
 #[derive(Debug)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 pub struct FsInfo {
     /// File system block size
     pub bsize: usize,
@@ -333,10 +486,15 @@ pub enum FsError {
     DeviceError,
     IOCTLError,
     NoDevice,
-    Again,       // E_AGAIN, when no data is available, never happens in fs
-    SymLoop,     // E_LOOP
-    Busy,        // E_BUSY
-    Interrupted, // E_INTR
+    Again,                 // E_AGAIN, when no data is available, never happens in fs
+    SymLoop,               // E_LOOP
+    Busy,                  // E_BUSY
+    Interrupted,           // E_INTR
+    TimedOut,              // E_TIMEDOUT, when a device operation is cancelled
+    BadFd,                 // E_BADF, when reading a write-only file or writing a read-only file
+    OperationNotPermitted, // E_PERM, when modifying an immutable or append-only file
+    Corrupted,             // E_BADMSG, when data fails an integrity check
+    ReadOnlyFs,            // E_ROFS, when modifying a read-only file system
 }
 
 impl fmt::Display for FsError {
@@ -346,8 +504,11 @@ impl fmt::Display for FsError {
 }
 
 impl From<DevError> for FsError {
-    fn from(_: DevError) -> Self {
-        FsError::DeviceError
+    fn from(e: DevError) -> Self {
+        match e {
+            DevError::IOError => FsError::DeviceError,
+            DevError::TimedOut => FsError::TimedOut,
+        }
     }
 }
 
@@ -361,8 +522,22 @@ pub trait FileSystem: Sync + Send {
     /// Get the root INode of the file system
     fn root_inode(&self) -> Arc<dyn INode>;
 
+    /// Get an additional root INode `name`, for file systems holding several trees.
+    ///
+    /// Absolute paths are still resolved from `root_inode()`.
+    fn root_named(&self, _name: &str) -> Result<Arc<dyn INode>> {
+        Err(FsError::NotSupported)
+    }
+
     /// Get the file system information
     fn info(&self) -> FsInfo;
+
+    /// Perform at most `budget` units of background work, return the units performed.
+    ///
+    /// See `maintenance::MaintenanceTask`.
+    fn run_maintenance(&self, _budget: usize) -> Result<usize> {
+        Ok(0)
+    }
 }
 
 pub fn make_rdev(major: usize, minor: usize) -> usize {
@@ -92,15 +92,18 @@ fn main() {
         Cmd::Unzip => false,
     };
 
-    let device = sgx_dev::SgxStorage::new(enclave.geteid(), &opt.image);
     let fs = match create {
         true => {
             std::fs::create_dir(&opt.image)
                 .expect("failed to create dir for SEFS");
-            sefs::SEFS::create(Box::new(device), &StdTimeProvider)
+            let device = sgx_dev::SgxStorage::create(enclave.geteid(), &opt.image, &sgx_dev::SgxRngProvider)
+                .expect("failed to create storage");
+            sefs::SEFS::create(Box::new(device), &StdTimeProvider, &sgx_dev::SgxRngProvider)
                 .expect("failed to create sefs")
         }
         false => {
+            let device = sgx_dev::SgxStorage::open(enclave.geteid(), &opt.image)
+                .expect("failed to open storage");
             sefs::SEFS::open(Box::new(device), &StdTimeProvider)
                 .expect("failed to open sefs")
         }
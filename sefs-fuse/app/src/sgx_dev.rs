@@ -1,16 +1,55 @@
 use sgx_types::*;
-use rcore_fs_sefs::dev::{File, Storage, DevResult, DeviceError};
+use rcore_fs::dev::{check_cancel, CancelToken, DevError, RngProvider};
+use rcore_fs_sefs::dev::{parse_file_id, File, Storage, DevResult, DeviceError};
 use std::path::*;
-use std::fs::remove_file;
+use std::fs::{metadata, read_dir, remove_file};
+use std::ptr;
 
 pub struct SgxStorage {
     path: PathBuf,
+    /// Key of the files, kept in `KEY_FILE` under the enclave's own key
+    key: sgx_key_128bit_t,
 }
 
+/// Name of the file keeping the key, never taken for a file id
+const KEY_FILE: &str = "key";
+
 impl SgxStorage {
-    pub fn new(eid: sgx_enclave_id_t, path: impl AsRef<Path>) -> Self {
+    /// Open the storage in `path` made by `create()`.
+    ///
+    /// Storages made before the key was generated have no `KEY_FILE`, and
+    /// keep using the zero key.
+    pub fn open(eid: sgx_enclave_id_t, path: impl AsRef<Path>) -> DevResult<Self> {
         unsafe { EID = eid; }
-        SgxStorage { path: path.as_ref().to_path_buf() }
+        let path = path.as_ref().to_path_buf();
+        let key_path = path.join(KEY_FILE);
+        let mut key = [0u8; 16];
+        if key_path.exists() {
+            let file = SgxFile { file: file_open(key_path.to_str().unwrap(), false, None) };
+            if file.read_at(&mut key, 0)? != key.len() {
+                return Err(DeviceError::IOError);
+            }
+        }
+        Ok(SgxStorage { path, key })
+    }
+
+    /// Make a storage in the existing dir `path`, with a key from `rng_provider`
+    pub fn create(
+        eid: sgx_enclave_id_t,
+        path: impl AsRef<Path>,
+        rng_provider: &dyn RngProvider,
+    ) -> DevResult<Self> {
+        unsafe { EID = eid; }
+        let path = path.as_ref().to_path_buf();
+        let mut key = [0u8; 16];
+        rng_provider.fill_bytes(&mut key)?;
+        let key_path = path.join(KEY_FILE);
+        let file = SgxFile { file: file_open(key_path.to_str().unwrap(), true, None) };
+        if file.write_at(&key, 0)? != key.len() {
+            return Err(DeviceError::IOError);
+        }
+        file.flush()?;
+        Ok(SgxStorage { path, key })
     }
 }
 
@@ -18,14 +57,14 @@ impl Storage for SgxStorage {
     fn open(&self, file_id: usize) -> DevResult<Box<File>> {
         let mut path = self.path.clone();
         path.push(format!("{}", file_id));
-        let file = file_open(path.to_str().unwrap(), false, &[0u8; 16]);
+        let file = file_open(path.to_str().unwrap(), false, Some(&self.key));
         Ok(Box::new(SgxFile { file }))
     }
 
     fn create(&self, file_id: usize) -> DevResult<Box<File>> {
         let mut path = self.path.clone();
         path.push(format!("{}", file_id));
-        let file = file_open(path.to_str().unwrap(), true, &[0u8; 16]);
+        let file = file_open(path.to_str().unwrap(), true, Some(&self.key));
         Ok(Box::new(SgxFile { file }))
     }
 
@@ -37,12 +76,36 @@ impl Storage for SgxStorage {
             Err(_) => panic!(),
         }
     }
+
+    fn list(&self) -> DevResult<Vec<usize>> {
+        let mut ids = Vec::new();
+        for entry in read_dir(&self.path).map_err(|_| DeviceError::IOError)? {
+            let entry = entry.map_err(|_| DeviceError::IOError)?;
+            // skip files not created by us
+            if let Some(id) = entry.file_name().to_str().and_then(parse_file_id) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn stat(&self, file_id: usize) -> DevResult<usize> {
+        let mut path = self.path.to_path_buf();
+        path.push(format!("{}", file_id));
+        match metadata(path) {
+            Ok(m) => Ok(m.len() as usize),
+            Err(_) => Err(DeviceError::IOError),
+        }
+    }
 }
 
 pub struct SgxFile {
     file: usize,
 }
 
+/// Largest IO done in one ecall, cancellation is checked between ecalls
+const CANCEL_CHUNK_SIZE: usize = 0x10000;
+
 impl File for SgxFile {
     fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
         match file_read_at(self.file, offset, buf) {
@@ -71,6 +134,32 @@ impl File for SgxFile {
             e => panic!("flush {}", e),
         }
     }
+
+    fn read_at_cancellable(&self, buf: &mut [u8], offset: usize, token: Option<&dyn CancelToken>) -> DevResult<usize> {
+        let mut len = 0;
+        for chunk in buf.chunks_mut(CANCEL_CHUNK_SIZE) {
+            check_cancel(token)?;
+            let chunk_len = self.read_at(chunk, offset + len)?;
+            len += chunk_len;
+            if chunk_len < chunk.len() {
+                break;
+            }
+        }
+        Ok(len)
+    }
+
+    fn write_at_cancellable(&self, buf: &[u8], offset: usize, token: Option<&dyn CancelToken>) -> DevResult<usize> {
+        let mut len = 0;
+        for chunk in buf.chunks(CANCEL_CHUNK_SIZE) {
+            check_cancel(token)?;
+            let chunk_len = self.write_at(chunk, offset + len)?;
+            len += chunk_len;
+            if chunk_len < chunk.len() {
+                break;
+            }
+        }
+        Ok(len)
+    }
 }
 
 impl Drop for SgxFile {
@@ -79,6 +168,18 @@ impl Drop for SgxFile {
     }
 }
 
+/// Random numbers from the enclave
+pub struct SgxRngProvider;
+
+impl RngProvider for SgxRngProvider {
+    fn fill_bytes(&self, buf: &mut [u8]) -> Result<(), DevError> {
+        match read_rand(buf) {
+            0 => Ok(()),
+            _ => Err(DevError::IOError),
+        }
+    }
+}
+
 /// Ecall functions to access SgxFile
 extern {
     fn ecall_file_open(eid: sgx_enclave_id_t, retval: *mut size_t, path: *const u8, create: uint8_t, key: *const sgx_key_128bit_t) -> sgx_status_t;
@@ -87,14 +188,17 @@ extern {
     fn ecall_file_read_at(eid: sgx_enclave_id_t, retval: *mut i32, fd: size_t, offset: size_t, buf: *mut uint8_t, len: size_t) -> sgx_status_t;
     fn ecall_file_write_at(eid: sgx_enclave_id_t, retval: *mut i32, fd: size_t, offset: size_t, buf: *const uint8_t, len: size_t) -> sgx_status_t;
     fn ecall_file_set_len(eid: sgx_enclave_id_t, retval: *mut i32, fd: size_t, len: size_t) -> sgx_status_t;
+    fn ecall_read_rand(eid: sgx_enclave_id_t, retval: *mut i32, buf: *mut uint8_t, len: size_t) -> sgx_status_t;
 }
 
 /// Must be set when init enclave
 static mut EID: sgx_enclave_id_t = 0;
 
 
-fn file_open(path: &str, create: bool, key: &sgx_key_128bit_t) -> usize {
+/// Open the file at `path` with `key`, or with a key of the enclave if `None`
+fn file_open(path: &str, create: bool, key: Option<&sgx_key_128bit_t>) -> usize {
     let cpath = format!("{}\0", path);
+    let key = key.map_or(ptr::null(), |key| key as *const _);
     let mut ret_val = 0;
     unsafe {
         let ret = ecall_file_open(EID, &mut ret_val, cpath.as_ptr(), create as uint8_t, key);
@@ -148,3 +252,12 @@ fn file_set_len(fd: usize, len: usize) -> i32 {
     }
     ret_val
 }
+
+fn read_rand(buf: &mut [u8]) -> i32 {
+    let mut ret_val = -1;
+    unsafe {
+        let ret = ecall_read_rand(EID, &mut ret_val, buf.as_mut_ptr(), buf.len());
+        assert_eq!(ret, sgx_status_t::SGX_SUCCESS);
+    }
+    ret_val
+}
@@ -53,12 +53,16 @@ macro_rules! try_io {
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn ecall_file_open(path: *const u8, create: bool, key: &SGX_KEY) -> *mut u8 {
+pub unsafe extern "C" fn ecall_file_open(path: *const u8, create: bool, key: *const SGX_KEY) -> *mut u8 {
     let mode = match create {
         true => "w+b\0",
         false => "r+b\0",
     };
-    let file = sgx_fopen(path, mode.as_ptr(), key);
+    // no key: use one derived from the enclave
+    let file = match key.is_null() {
+        true => sgx_fopen_auto_key(path, mode.as_ptr()),
+        false => sgx_fopen(path, mode.as_ptr(), key),
+    };
     file
 }
 
@@ -108,3 +112,15 @@ pub unsafe extern "C" fn ecall_file_set_len(file: SGX_FILE, len: usize) -> i32 {
     // TODO: how to shrink a file?
     0
 }
+
+extern {
+    fn sgx_read_rand(rand: *mut u8, length_in_bytes: usize) -> u32;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_read_rand(buf: *mut u8, len: usize) -> i32 {
+    match sgx_read_rand(buf, len) {
+        0 => 0,
+        _ => -1,
+    }
+}